@@ -4,11 +4,11 @@ use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use random_access_disk as rad;
 use random_access_storage::RandomAccess;
 
-#[cfg(feature = "async-std")]
+#[cfg(not(feature = "tokio"))]
 use criterion::async_executor::AsyncStdExecutor;
 
 fn bench_write_hello_world(c: &mut Criterion) {
-  #[cfg(feature = "async-std")]
+  #[cfg(not(feature = "tokio"))]
   c.bench_function("write hello world", |b| {
     b.to_async(AsyncStdExecutor).iter_custom(write_hello_world);
   });
@@ -36,7 +36,7 @@ async fn write_hello_world(iters: u64) -> Duration {
 }
 
 fn bench_read_hello_world(c: &mut Criterion) {
-  #[cfg(feature = "async-std")]
+  #[cfg(not(feature = "tokio"))]
   c.bench_function("read hello world", |b| {
     b.to_async(AsyncStdExecutor).iter_custom(read_hello_world);
   });
@@ -65,7 +65,7 @@ async fn read_hello_world(iters: u64) -> Duration {
 }
 
 fn bench_read_write_hello_world(c: &mut Criterion) {
-  #[cfg(feature = "async-std")]
+  #[cfg(not(feature = "tokio"))]
   c.bench_function("read/write hello world", |b| {
     b.to_async(AsyncStdExecutor)
       .iter_custom(read_write_hello_world);
@@ -95,7 +95,7 @@ async fn read_write_hello_world(iters: u64) -> Duration {
 }
 
 fn bench_write_del_hello_world(c: &mut Criterion) {
-  #[cfg(feature = "async-std")]
+  #[cfg(not(feature = "tokio"))]
   c.bench_function("write/del hello world", |b| {
     b.to_async(AsyncStdExecutor)
       .iter_custom(write_del_hello_world);
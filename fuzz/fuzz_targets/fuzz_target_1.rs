@@ -6,6 +6,7 @@ use self::tempdir::TempDir;
 
 fuzz_target!(|data: &[u8]| {
   let dir = TempDir::new("random-access-disk").unwrap();
-  let mut file = rad::Sync::new(dir.path().join("2.db"));
+  let mut file =
+    rad::blocking::RandomAccessDisk::open(dir.path().join("2.db")).unwrap();
   file.write(0, data).unwrap();
 });
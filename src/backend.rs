@@ -0,0 +1,397 @@
+//! Runs file operations for [crate::RandomAccessDisk], either on the
+//! runtime's blocking thread pool or, with the `io-uring` feature on Linux,
+//! through io_uring. With [crate::Builder::direct_io], always on the blocking
+//! thread pool with block-aligned I/O.
+
+use crate::batch::{self, Run};
+use crate::drop_strategy::Pending;
+use crate::runtime::unblock;
+use crate::sync_policy::SyncKind;
+use crate::trim_mode::{TrimMode, TrimState};
+use crate::{direct, positional, preallocate, trim, zero_range, DiskError};
+use random_access_storage::RandomAccessError;
+use std::fs;
+use std::io::{self, IoSlice};
+use std::sync::Arc;
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::Ring;
+
+#[derive(Debug, Clone)]
+pub struct Backend {
+  file: Arc<fs::File>,
+  // Alignment when doing direct I/O.
+  direct: Option<u64>,
+  trim: Arc<TrimState>,
+  #[cfg(all(feature = "io-uring", target_os = "linux"))]
+  ring: Option<&'static Ring>,
+}
+
+impl Backend {
+  pub fn new(file: fs::File, direct: Option<u64>, trim_mode: TrimMode) -> Self {
+    Self {
+      file: Arc::new(file),
+      direct,
+      trim: Arc::new(TrimState::new(trim_mode)),
+      #[cfg(all(feature = "io-uring", target_os = "linux"))]
+      ring: if direct.is_none() { Ring::get() } else { None },
+    }
+  }
+
+  pub fn file(&self) -> &Arc<fs::File> {
+    &self.file
+  }
+
+  /// Run `f` with the file on the blocking thread pool.
+  pub async fn unblock<F, T>(&self, f: F) -> T
+  where
+    F: FnOnce(&fs::File) -> T + Send + 'static,
+    T: Send + 'static,
+  {
+    let file = self.file.clone();
+    unblock(move || f(&file)).await
+  }
+
+  /// Read `length` bytes at `offset`, failing with [DiskError::ShortRead] if
+  /// the file ends before that.
+  pub async fn read_at(
+    &self,
+    offset: u64,
+    length: u64,
+  ) -> Result<Vec<u8>, RandomAccessError> {
+    self.read_to(offset, vec![0; length as usize]).await
+  }
+
+  /// Fill `buffer` from `offset` and hand it back, failing with
+  /// [DiskError::ShortRead] if the file ends before that.
+  pub async fn read_to(
+    &self,
+    offset: u64,
+    buffer: Vec<u8>,
+  ) -> Result<Vec<u8>, RandomAccessError> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if let Some(ring) = self.ring {
+      let (buffer, bytes_read) =
+        ring.read_at(&self.file, offset, buffer).await?;
+      return check_full_read(buffer, bytes_read, offset);
+    }
+    if let Some(align) = self.direct {
+      let length = buffer.len() as u64;
+      return self
+        .unblock(move |file| {
+          let (buffer, bytes_read) =
+            direct::read_at_aligned(file, offset, length, align)?;
+          check_full_read(buffer, bytes_read, offset)
+        })
+        .await;
+    }
+    self
+      .unblock(move |file| {
+        let mut buffer = buffer;
+        let bytes_read = positional::read_fully_at(file, &mut buffer, offset)?;
+        check_full_read(buffer, bytes_read, offset)
+      })
+      .await
+  }
+
+  /// Read each `(offset, length)` of `ranges`, see [batch::read_ranges].
+  pub async fn read_batch(
+    &self,
+    ranges: Vec<(u64, u64)>,
+  ) -> Vec<Result<Vec<u8>, RandomAccessError>> {
+    let direct = self.direct;
+    self
+      .unblock(move |file| match direct {
+        Some(align) => ranges
+          .into_iter()
+          .map(|(offset, length)| {
+            let (buffer, bytes_read) =
+              direct::read_at_aligned(file, offset, length, align)?;
+            check_full_read(buffer, bytes_read, offset)
+          })
+          .collect(),
+        None => batch::read_ranges(file, &ranges),
+      })
+      .await
+  }
+
+  /// Write all of `data` at `offset`, then sync if asked to.
+  pub async fn write_at(
+    &self,
+    offset: u64,
+    data: Vec<u8>,
+    sync: Option<SyncKind>,
+  ) -> Result<(), RandomAccessError> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if let Some(ring) = self.ring {
+      ring.write_all_at(&self.file, offset, data).await?;
+      return self.sync_if(sync).await;
+    }
+    let direct = self.direct;
+    self
+      .unblock(move |file| {
+        match direct {
+          Some(align) => {
+            direct::write_all_at_aligned(file, &data, offset, align)?
+          }
+          None => positional::write_all_at(file, &data, offset)?,
+        }
+        sync_blocking(file, sync)
+      })
+      .await
+  }
+
+  /// Write `runs` in order, each with a single vectored write, then grow the
+  /// file to `min_length` if it's shorter, then sync if asked to.
+  pub async fn write_batch(
+    &self,
+    runs: Vec<Run>,
+    min_length: u64,
+    sync: Option<SyncKind>,
+  ) -> Result<(), RandomAccessError> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if let Some(ring) = self.ring {
+      let mut end = 0;
+      for run in runs {
+        end = end.max(run.end);
+        ring
+          .write_all_at(&self.file, run.offset, run.concat())
+          .await?;
+      }
+      if end < min_length {
+        self.set_len(min_length, None).await?;
+      }
+      return self.sync_if(sync).await;
+    }
+    let direct = self.direct;
+    self
+      .unblock(move |file| {
+        write_runs(file, direct, runs, min_length)?;
+        sync_blocking(file, sync)
+      })
+      .await
+  }
+
+  /// [Backend::write_batch] without syncing, to be run later on the file.
+  pub fn pending(&self, runs: Vec<Run>, min_length: u64) -> Pending {
+    let direct = self.direct;
+    Box::new(move |file| write_runs(file, direct, runs, min_length))
+  }
+
+  /// Set the length of the file, then sync if asked to.
+  pub async fn set_len(
+    &self,
+    length: u64,
+    sync: Option<SyncKind>,
+  ) -> Result<(), RandomAccessError> {
+    // There's no truncate in io_uring before Linux 6.9, and it's rare enough
+    // to not matter anyway.
+    self
+      .unblock(move |file| {
+        file.set_len(length)?;
+        sync_blocking(file, sync)
+      })
+      .await
+  }
+
+  /// Zero out, or punch a hole to, `length` bytes at `offset`, then sync if
+  /// asked to.
+  pub async fn trim(
+    &self,
+    offset: u64,
+    length: u64,
+    block_size: u64,
+    sync: Option<SyncKind>,
+  ) -> Result<(), RandomAccessError> {
+    #[cfg(all(feature = "io-uring", feature = "sparse", target_os = "linux"))]
+    if let (Some(ring), true) = (self.ring, self.trim.punch_holes()) {
+      use libc::{FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE};
+      match ring
+        .fallocate(
+          &self.file,
+          offset,
+          length,
+          FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE,
+        )
+        .await
+      {
+        Ok(()) => return self.sync_if(sync).await,
+        // Left to writing zeros below.
+        Err(source) => self.trim.fallback(RandomAccessError::IO {
+          context: Some("Failed to punch hole to file on linux".to_string()),
+          return_code: source.raw_os_error(),
+          source,
+        })?,
+      }
+    }
+    let direct = self.direct;
+    let state = self.trim.clone();
+    self
+      .unblock(move |file| {
+        match direct {
+          Some(align) => state.trim(
+            || direct::trim_aligned(file, offset, length, align),
+            || direct::write_zeros_aligned(file, offset, length, align),
+          )?,
+          None => state.trim(
+            || trim(file, offset, length, block_size),
+            || positional::write_zeros(file, offset, length),
+          )?,
+        }
+        sync_blocking(file, sync)
+      })
+      .await
+  }
+
+  /// Zero out `length` bytes at `offset`, keeping them allocated, then sync
+  /// if asked to.
+  pub async fn zero(
+    &self,
+    offset: u64,
+    length: u64,
+    sync: Option<SyncKind>,
+  ) -> Result<(), RandomAccessError> {
+    #[cfg(all(feature = "io-uring", feature = "sparse", target_os = "linux"))]
+    if let Some(ring) = self.ring {
+      use libc::{FALLOC_FL_KEEP_SIZE, FALLOC_FL_ZERO_RANGE};
+      match ring
+        .fallocate(
+          &self.file,
+          offset,
+          length,
+          FALLOC_FL_ZERO_RANGE | FALLOC_FL_KEEP_SIZE,
+        )
+        .await
+      {
+        Ok(()) => return self.sync_if(sync).await,
+        // Left to writing zeros below.
+        Err(err) if crate::trim_mode::is_unsupported(&err) => {}
+        Err(source) => {
+          return Err(RandomAccessError::IO {
+            context: Some("Failed to zero range of file on linux".to_string()),
+            return_code: source.raw_os_error(),
+            source,
+          })
+        }
+      }
+    }
+    let direct = self.direct;
+    self
+      .unblock(move |file| {
+        match direct {
+          Some(align) => {
+            direct::write_zeros_aligned(file, offset, length, align)?
+          }
+          None => zero_range(file, offset, length)?,
+        }
+        sync_blocking(file, sync)
+      })
+      .await
+  }
+
+  /// Reserve disk blocks for `length` bytes at `offset`, without changing
+  /// the length of the file.
+  pub async fn preallocate(
+    &self,
+    offset: u64,
+    length: u64,
+  ) -> Result<(), RandomAccessError> {
+    #[cfg(all(feature = "io-uring", feature = "sparse", target_os = "linux"))]
+    if let Some(ring) = self.ring {
+      return match ring
+        .fallocate(&self.file, offset, length, libc::FALLOC_FL_KEEP_SIZE)
+        .await
+      {
+        Err(source) if !crate::trim_mode::is_unsupported(&source) => {
+          Err(RandomAccessError::IO {
+            context: Some("Failed to preallocate file on linux".to_string()),
+            return_code: source.raw_os_error(),
+            source,
+          })
+        }
+        _ => Ok(()),
+      };
+    }
+    self
+      .unblock(move |file| preallocate(file, offset, length))
+      .await
+  }
+
+  /// Sync the file.
+  pub async fn sync(&self, kind: SyncKind) -> Result<(), RandomAccessError> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if let Some(ring) = self.ring {
+      ring.sync(&self.file, kind).await?;
+      return Ok(());
+    }
+    self
+      .unblock(move |file| kind.sync(file))
+      .await
+      .map_err(Into::into)
+  }
+
+  #[cfg(all(feature = "io-uring", target_os = "linux"))]
+  async fn sync_if(
+    &self,
+    sync: Option<SyncKind>,
+  ) -> Result<(), RandomAccessError> {
+    match sync {
+      Some(kind) => self.sync(kind).await,
+      None => Ok(()),
+    }
+  }
+}
+
+fn write_runs(
+  file: &fs::File,
+  direct: Option<u64>,
+  runs: Vec<Run>,
+  min_length: u64,
+) -> io::Result<()> {
+  let mut end = 0;
+  for run in runs {
+    end = end.max(run.end);
+    match direct {
+      Some(align) => {
+        direct::write_all_at_aligned(file, &run.concat(), run.offset, align)?
+      }
+      None => {
+        let mut bufs: Vec<_> =
+          run.chunks.iter().map(|chunk| IoSlice::new(chunk)).collect();
+        positional::write_all_vectored_at(file, &mut bufs, run.offset)?
+      }
+    }
+  }
+  if end < min_length {
+    file.set_len(min_length)?;
+  }
+  Ok(())
+}
+
+fn sync_blocking(
+  file: &fs::File,
+  sync: Option<SyncKind>,
+) -> Result<(), RandomAccessError> {
+  if let Some(kind) = sync {
+    kind.sync(file)?;
+  }
+  Ok(())
+}
+
+fn check_full_read(
+  buffer: Vec<u8>,
+  bytes_read: usize,
+  offset: u64,
+) -> Result<Vec<u8>, RandomAccessError> {
+  if bytes_read < buffer.len() {
+    return Err(
+      DiskError::ShortRead {
+        offset,
+        expected: buffer.len() as u64,
+        actual: bytes_read as u64,
+      }
+      .into(),
+    );
+  }
+  Ok(buffer)
+}
@@ -0,0 +1,198 @@
+use crate::{positional, DiskError};
+use random_access_storage::{RandomAccess, RandomAccessError};
+use std::fs;
+use std::io::{self, IoSliceMut};
+
+/// How far apart ranges of a batch read can be and still be read together.
+const MAX_GAP: u64 = 4096;
+
+/// Extension of [RandomAccess] for reading and writing many ranges at once,
+/// and for reading into buffers of the caller.
+///
+/// The default implementations call [RandomAccess::read] and
+/// [RandomAccess::write] for each range, so any [RandomAccess]
+/// implementation can get them with an empty `impl` block, and implement
+/// them better where it can.
+#[async_trait::async_trait]
+pub trait RandomAccessBatch: RandomAccess {
+  /// Write each `(offset, data)` of `ops`. The result is the same as writing
+  /// them one by one in order, so where ranges overlap the later one wins,
+  /// but they may be written in any order and synced only once. If this
+  /// fails, any of the writes may or may not have been done.
+  async fn write_batch(
+    &mut self,
+    ops: &[(u64, &[u8])],
+  ) -> Result<(), RandomAccessError> {
+    for (offset, data) in ops {
+      self.write(*offset, data).await?;
+    }
+    Ok(())
+  }
+
+  /// Read `buffer.len()` bytes at `offset` into `buffer`. Fails like
+  /// [RandomAccess::read], in which case `buffer` may have been written to.
+  async fn read_into(
+    &mut self,
+    offset: u64,
+    buffer: &mut [u8],
+  ) -> Result<(), RandomAccessError> {
+    let data = self.read(offset, buffer.len() as u64).await?;
+    buffer.copy_from_slice(&data);
+    Ok(())
+  }
+
+  /// Read each `(offset, length)` of `ranges`, returning what
+  /// [RandomAccess::read] would for each of them, in the same order.
+  async fn read_batch(
+    &mut self,
+    ranges: &[(u64, u64)],
+  ) -> Vec<Result<Vec<u8>, RandomAccessError>> {
+    let mut results = Vec::with_capacity(ranges.len());
+    for (offset, length) in ranges {
+      results.push(self.read(*offset, *length).await);
+    }
+    results
+  }
+}
+
+/// Adjacent writes of a batch, to be written together.
+#[derive(Debug)]
+pub struct Run {
+  pub offset: u64,
+  pub end: u64,
+  pub chunks: Vec<Vec<u8>>,
+}
+
+impl Run {
+  /// All chunks in one buffer.
+  pub fn concat(&self) -> Vec<u8> {
+    self.chunks.concat()
+  }
+}
+
+/// Turn `ops` into runs of adjacent writes. They are sorted by offset,
+/// unless some of them overlap, in which case their order matters and is
+/// kept. Empty writes are left out.
+pub fn plan(ops: &[(u64, &[u8])]) -> Vec<Run> {
+  let mut ops: Vec<_> =
+    ops.iter().filter(|(_, data)| !data.is_empty()).collect();
+  let mut sorted = ops.clone();
+  sorted.sort_by_key(|(offset, _)| *offset);
+  let overlaps = sorted
+    .windows(2)
+    .any(|pair| pair[0].0 + pair[0].1.len() as u64 > pair[1].0);
+  if !overlaps {
+    ops = sorted;
+  }
+
+  let mut runs: Vec<Run> = vec![];
+  for (offset, data) in ops {
+    let end = offset + data.len() as u64;
+    match runs.last_mut() {
+      Some(run) if run.end == *offset => {
+        run.end = end;
+        run.chunks.push(data.to_vec());
+      }
+      _ => runs.push(Run {
+        offset: *offset,
+        end,
+        chunks: vec![data.to_vec()],
+      }),
+    }
+  }
+  runs
+}
+
+/// Read each `(offset, length)` of `ranges` from `file`, reading ranges that
+/// are close to each other with one vectored read. Ranges the file ends
+/// before fail with [DiskError::ShortRead].
+pub fn read_ranges(
+  file: &fs::File,
+  ranges: &[(u64, u64)],
+) -> Vec<Result<Vec<u8>, RandomAccessError>> {
+  let mut order: Vec<usize> = (0..ranges.len()).collect();
+  order.sort_by_key(|&i| ranges[i].0);
+
+  let mut results: Vec<_> = ranges.iter().map(|_| None).collect();
+  let mut group: Vec<usize> = vec![];
+  let mut group_end = 0;
+  for i in order {
+    let (offset, length) = ranges[i];
+    // Overlapping ranges can't be read into separate buffers at once.
+    if !group.is_empty() && (offset < group_end || offset - group_end > MAX_GAP)
+    {
+      read_group(file, ranges, &group, &mut results);
+      group.clear();
+    }
+    group.push(i);
+    group_end = offset + length;
+  }
+  if !group.is_empty() {
+    read_group(file, ranges, &group, &mut results);
+  }
+  results.into_iter().map(Option::unwrap).collect()
+}
+
+/// Read the sorted, non-overlapping `group` of `ranges` in one go, reading
+/// the gaps between them into a scratch buffer.
+fn read_group(
+  file: &fs::File,
+  ranges: &[(u64, u64)],
+  group: &[usize],
+  results: &mut [Option<Result<Vec<u8>, RandomAccessError>>],
+) {
+  let start = ranges[group[0]].0;
+  let mut buffers: Vec<Vec<u8>> = group
+    .iter()
+    .map(|&i| vec![0; ranges[i].1 as usize])
+    .collect();
+  let gaps: Vec<usize> = group
+    .windows(2)
+    .map(|pair| {
+      let (offset, length) = ranges[pair[0]];
+      (ranges[pair[1]].0 - (offset + length)) as usize
+    })
+    .collect();
+  let mut gap_buffer = vec![0; gaps.iter().sum()];
+
+  let mut slices = Vec::with_capacity(group.len() * 2);
+  let mut rest = gap_buffer.as_mut_slice();
+  for (j, buffer) in buffers.iter_mut().enumerate() {
+    slices.push(IoSliceMut::new(buffer));
+    if let Some(&gap) = gaps.get(j) {
+      let (gap, tail) = std::mem::take(&mut rest).split_at_mut(gap);
+      rest = tail;
+      slices.push(IoSliceMut::new(gap));
+    }
+  }
+  let read = positional::read_fully_vectored_at(file, &mut slices, start);
+  drop(slices);
+
+  match read {
+    Ok(bytes_read) => {
+      let read_end = start + bytes_read as u64;
+      for (&i, buffer) in group.iter().zip(buffers) {
+        let (offset, length) = ranges[i];
+        let actual = read_end.saturating_sub(offset).min(length);
+        results[i] = Some(if actual < length {
+          Err(
+            DiskError::ShortRead {
+              offset,
+              expected: length,
+              actual,
+            }
+            .into(),
+          )
+        } else {
+          Ok(buffer)
+        });
+      }
+    }
+    Err(err) => {
+      for &i in group {
+        let err = io::Error::new(err.kind(), err.to_string());
+        results[i] = Some(Err(err.into()));
+      }
+    }
+  }
+}
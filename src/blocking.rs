@@ -0,0 +1,180 @@
+//! Synchronous API, for use without an async runtime.
+//!
+//! [RandomAccessDisk] here has the same operations as
+//! [crate::RandomAccessDisk], and is built with the same [Builder] options,
+//! but each call blocks the calling thread until done. File operations are
+//! done right on the calling thread, rather than on a thread pool.
+//!
+//! ```
+//! use random_access_disk::blocking::RandomAccessDisk;
+//!
+//! let path = tempfile::Builder::new().prefix("blocking").tempfile().unwrap().into_temp_path();
+//! let mut storage = RandomAccessDisk::open(&path).unwrap();
+//! storage.write(0, b"hello").unwrap();
+//! storage.write(5, b" world").unwrap();
+//! assert_eq!(storage.read(0, 11).unwrap(), b"hello world");
+//! storage.close().unwrap();
+//! ```
+
+use crate::runtime::block_on;
+use crate::{Builder, RandomAccessBatch, ReadCacheStats};
+use random_access_storage::{RandomAccess, RandomAccessError};
+use std::ops::Range;
+use std::path;
+
+/// Synchronous counterpart of [crate::RandomAccessDisk]. Create one with
+/// [RandomAccessDisk::open] or [Builder::build_blocking].
+#[derive(Debug)]
+pub struct RandomAccessDisk {
+  inner: crate::RandomAccessDisk,
+}
+
+impl RandomAccessDisk {
+  pub(crate) fn new(inner: crate::RandomAccessDisk) -> Self {
+    Self { inner }
+  }
+
+  /// Create a new (auto-sync) instance to storage at `filename`.
+  pub fn open(
+    filename: impl AsRef<path::Path>,
+  ) -> Result<RandomAccessDisk, RandomAccessError> {
+    Self::builder(filename).build_blocking()
+  }
+
+  /// Initialize a builder with storage at `filename`, to be built with
+  /// [Builder::build_blocking].
+  pub fn builder(filename: impl AsRef<path::Path>) -> Builder {
+    Builder::new(filename)
+  }
+
+  /// Write bytes of `data` at an `offset`, see [RandomAccess::write].
+  pub fn write(
+    &mut self,
+    offset: u64,
+    data: &[u8],
+  ) -> Result<(), RandomAccessError> {
+    block_on(self.inner.write(offset, data))
+  }
+
+  /// Read a sequence of bytes at an `offset`, see [RandomAccess::read].
+  pub fn read(
+    &mut self,
+    offset: u64,
+    length: u64,
+  ) -> Result<Vec<u8>, RandomAccessError> {
+    block_on(self.inner.read(offset, length))
+  }
+
+  /// Delete a sequence of bytes at an `offset`, see [RandomAccess::del].
+  pub fn del(
+    &mut self,
+    offset: u64,
+    length: u64,
+  ) -> Result<(), RandomAccessError> {
+    block_on(self.inner.del(offset, length))
+  }
+
+  /// Resize to `length`, see [RandomAccess::truncate].
+  pub fn truncate(&mut self, length: u64) -> Result<(), RandomAccessError> {
+    block_on(self.inner.truncate(length))
+  }
+
+  /// Get the size in bytes, see [RandomAccess::len].
+  pub fn len(&mut self) -> Result<u64, RandomAccessError> {
+    block_on(self.inner.len())
+  }
+
+  /// Whether the size is zero, see [RandomAccess::is_empty].
+  pub fn is_empty(&mut self) -> Result<bool, RandomAccessError> {
+    block_on(self.inner.is_empty())
+  }
+
+  /// Sync all changes to disk, see [RandomAccess::sync_all].
+  pub fn sync_all(&mut self) -> Result<(), RandomAccessError> {
+    block_on(self.inner.sync_all())
+  }
+
+  /// Sync, unlock and close, see [crate::RandomAccessDisk::close].
+  pub fn close(&mut self) -> Result<(), RandomAccessError> {
+    block_on(self.inner.close())
+  }
+
+  /// Write each `(offset, data)` of `ops`, see
+  /// [RandomAccessBatch::write_batch].
+  pub fn write_batch(
+    &mut self,
+    ops: &[(u64, &[u8])],
+  ) -> Result<(), RandomAccessError> {
+    block_on(self.inner.write_batch(ops))
+  }
+
+  /// Read `buffer.len()` bytes at `offset` into `buffer`, see
+  /// [RandomAccessBatch::read_into].
+  pub fn read_into(
+    &mut self,
+    offset: u64,
+    buffer: &mut [u8],
+  ) -> Result<(), RandomAccessError> {
+    block_on(self.inner.read_into(offset, buffer))
+  }
+
+  /// Read each `(offset, length)` of `ranges`, see
+  /// [RandomAccessBatch::read_batch].
+  pub fn read_batch(
+    &mut self,
+    ranges: &[(u64, u64)],
+  ) -> Vec<Result<Vec<u8>, RandomAccessError>> {
+    block_on(self.inner.read_batch(ranges))
+  }
+
+  /// See [crate::RandomAccessDisk::data_ranges].
+  pub fn data_ranges(
+    &mut self,
+    offset: u64,
+    length: u64,
+  ) -> Result<Vec<Range<u64>>, RandomAccessError> {
+    block_on(self.inner.data_ranges(offset, length))
+  }
+
+  /// See [crate::RandomAccessDisk::holes].
+  pub fn holes(
+    &mut self,
+    offset: u64,
+    length: u64,
+  ) -> Result<Vec<Range<u64>>, RandomAccessError> {
+    block_on(self.inner.holes(offset, length))
+  }
+
+  /// See [crate::RandomAccessDisk::zero].
+  pub fn zero(
+    &mut self,
+    offset: u64,
+    length: u64,
+  ) -> Result<(), RandomAccessError> {
+    block_on(self.inner.zero(offset, length))
+  }
+
+  /// See [crate::RandomAccessDisk::preallocate].
+  pub fn preallocate(
+    &mut self,
+    offset: u64,
+    length: u64,
+  ) -> Result<(), RandomAccessError> {
+    block_on(self.inner.preallocate(offset, length))
+  }
+
+  /// See [crate::RandomAccessDisk::allocated_size].
+  pub fn allocated_size(&mut self) -> Result<u64, RandomAccessError> {
+    block_on(self.inner.allocated_size())
+  }
+
+  /// See [crate::RandomAccessDisk::refresh].
+  pub fn refresh(&mut self) -> Result<(), RandomAccessError> {
+    block_on(self.inner.refresh())
+  }
+
+  /// See [crate::RandomAccessDisk::read_cache_stats].
+  pub fn read_cache_stats(&self) -> Option<ReadCacheStats> {
+    self.inner.read_cache_stats()
+  }
+}
@@ -1,41 +1,69 @@
-#[cfg(feature = "async-std")]
-use async_std::fs;
 use random_access_storage::RandomAccessError;
-#[cfg(feature = "tokio")]
-use tokio::fs;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
 
 /// Get file length and file system block size
-pub async fn get_length_and_block_size(
+pub fn get_length_and_block_size(
   file: &fs::File,
 ) -> Result<(u64, u64), RandomAccessError> {
-  let metadata = file.metadata().await?;
+  let metadata = file.metadata()?;
   Ok((metadata.len(), 0))
 }
 
+/// Get the space the file takes on disk, assumed to be its length
+pub fn allocated_size(
+  file: &fs::File,
+  _path: &Path,
+) -> Result<u64, RandomAccessError> {
+  Ok(file.metadata()?.len())
+}
+
 /// Set file to sparse, not applicable
-pub async fn set_sparse(_file: &mut fs::File) -> Result<(), RandomAccessError> {
+pub fn set_sparse(_file: &mut fs::File) -> Result<(), RandomAccessError> {
+  Ok(())
+}
+
+/// Reserving disk blocks, not applicable
+pub fn preallocate(
+  _file: &fs::File,
+  _offset: u64,
+  _length: u64,
+) -> Result<(), RandomAccessError> {
+  Ok(())
+}
+
+/// Zeroing of a range by writing zeros
+pub fn zero_range(
+  file: &fs::File,
+  offset: u64,
+  length: u64,
+) -> Result<(), RandomAccessError> {
+  crate::positional::write_zeros(file, offset, length)?;
   Ok(())
 }
 
 /// Non-sparse trimming of a file to zeros
-pub async fn trim(
-  file: &mut fs::File,
+pub fn trim(
+  file: &fs::File,
   offset: u64,
   length: u64,
   _block_size: u64,
 ) -> Result<(), RandomAccessError> {
-  #[cfg(feature = "async-std")]
-  use async_std::io::{
-    prelude::{SeekExt, WriteExt},
-    SeekFrom,
-  };
-  #[cfg(feature = "tokio")]
-  use std::io::SeekFrom;
-  #[cfg(feature = "tokio")]
-  use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+  use crate::positional::write_all_at;
 
   let data = vec![0_u8; length as usize];
-  file.seek(SeekFrom::Start(offset)).await?;
-  file.write_all(&data).await?;
+  write_all_at(file, &data, offset)?;
   Ok(())
 }
+
+/// Ranges of `length` bytes at `offset` that hold data, which without holes
+/// is all of it.
+#[allow(clippy::single_range_in_vec_init)]
+pub fn data_ranges(
+  _file: &fs::File,
+  offset: u64,
+  length: u64,
+) -> Result<Vec<Range<u64>>, RandomAccessError> {
+  Ok(vec![offset..offset + length])
+}
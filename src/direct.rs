@@ -0,0 +1,289 @@
+//! Unbuffered I/O, see [crate::Builder::direct_io].
+//!
+//! With `O_DIRECT`, offsets, lengths and buffer addresses all have to be
+//! aligned to the file system's block size. Unaligned reads read the whole
+//! blocks around them, and unaligned writes read, modify and write back the
+//! blocks at either end.
+
+use crate::positional::{read_at, write_all_at};
+use random_access_storage::RandomAccessError;
+use std::fs;
+use std::io;
+use std::ops::{Deref, DerefMut};
+
+/// Alignment to use for a file system with `block_size`.
+pub fn alignment(block_size: u64) -> u64 {
+  // At least the 512 byte sectors `O_DIRECT` usually wants, and never
+  // something that isn't a power of two.
+  block_size.max(512).next_power_of_two()
+}
+
+/// Open options for unbuffered I/O.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub fn set_open_options(
+  options: &mut fs::OpenOptions,
+) -> Result<(), RandomAccessError> {
+  use std::os::unix::fs::OpenOptionsExt;
+  options.custom_flags(libc::O_DIRECT);
+  Ok(())
+}
+
+/// Open options for unbuffered I/O, done with `F_NOCACHE` after opening on
+/// macOS.
+#[cfg(target_os = "macos")]
+pub fn set_open_options(
+  _options: &mut fs::OpenOptions,
+) -> Result<(), RandomAccessError> {
+  Ok(())
+}
+
+/// Open options for unbuffered I/O, not supported.
+#[cfg(not(any(
+  target_os = "linux",
+  target_os = "android",
+  target_os = "freebsd",
+  target_os = "macos",
+)))]
+pub fn set_open_options(
+  _options: &mut fs::OpenOptions,
+) -> Result<(), RandomAccessError> {
+  Err(
+    crate::DiskError::IncompatibleOptions(
+      "direct I/O is only supported on Linux, FreeBSD and macOS",
+    )
+    .into(),
+  )
+}
+
+/// Turn off caching for an opened file on macOS.
+#[cfg(target_os = "macos")]
+pub fn after_open(file: &fs::File) -> Result<(), RandomAccessError> {
+  use std::os::unix::io::AsRawFd;
+  let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) };
+  if ret < 0 {
+    return Err(RandomAccessError::IO {
+      context: Some("Failed to turn off caching on macos".to_string()),
+      return_code: Some(ret),
+      source: io::Error::last_os_error(),
+    });
+  }
+  Ok(())
+}
+
+/// Nothing to do once opened.
+#[cfg(not(target_os = "macos"))]
+pub fn after_open(_file: &fs::File) -> Result<(), RandomAccessError> {
+  Ok(())
+}
+
+/// Read `length` bytes at `offset` until done or the end of the file is
+/// reached. Returns the bytes, zero-filled past the end of the file, and how
+/// many were read.
+pub fn read_at_aligned(
+  file: &fs::File,
+  offset: u64,
+  length: u64,
+  align: u64,
+) -> io::Result<(Vec<u8>, usize)> {
+  let start = round_down(offset, align);
+  let end = round_up(offset + length, align);
+  let mut buffer = AlignedBuffer::new((end - start) as usize, align);
+  let filled = read_blocks(file, &mut buffer, start, align)?;
+  let skip = (offset - start) as usize;
+  let data = buffer[skip..skip + length as usize].to_vec();
+  Ok((data, filled.saturating_sub(skip).min(length as usize)))
+}
+
+/// Write all of `data` at `offset`.
+pub fn write_all_at_aligned(
+  file: &fs::File,
+  data: &[u8],
+  offset: u64,
+  align: u64,
+) -> io::Result<()> {
+  if data.is_empty() {
+    return Ok(());
+  }
+  let file_length = file.metadata()?.len();
+  let end = offset + data.len() as u64;
+  let start = round_down(offset, align);
+  let aligned_end = round_up(end, align);
+  let mut buffer = AlignedBuffer::new((aligned_end - start) as usize, align);
+
+  // Keep what's already in the partially written blocks at either end.
+  let last_block = aligned_end - align;
+  if offset > start && start < file_length {
+    read_blocks(file, &mut buffer[..align as usize], start, align)?;
+  }
+  if end < aligned_end
+    && last_block < file_length
+    && (last_block > start || offset == start)
+  {
+    let from = (last_block - start) as usize;
+    read_blocks(file, &mut buffer[from..], last_block, align)?;
+  }
+
+  let from = (offset - start) as usize;
+  buffer[from..from + data.len()].copy_from_slice(data);
+  write_all_at(file, &buffer, start)?;
+
+  // The last block may have been written past the end of the file.
+  let new_length = file_length.max(end);
+  if aligned_end > new_length {
+    file.set_len(new_length)?;
+  }
+  Ok(())
+}
+
+/// Zero out `length` bytes at `offset`, punching holes in the whole blocks
+/// in between where supported.
+pub fn trim_aligned(
+  file: &fs::File,
+  offset: u64,
+  length: u64,
+  align: u64,
+) -> Result<(), RandomAccessError> {
+  let end = offset + length;
+  let inner_start = round_up(offset, align);
+  let inner_end = round_down(end, align);
+  if inner_start >= inner_end {
+    write_all_at_aligned(file, &vec![0; length as usize], offset, align)?;
+    return Ok(());
+  }
+  if offset < inner_start {
+    let zeros = vec![0; (inner_start - offset) as usize];
+    write_all_at_aligned(file, &zeros, offset, align)?;
+  }
+  if inner_end < end {
+    let zeros = vec![0; (end - inner_end) as usize];
+    write_all_at_aligned(file, &zeros, inner_end, align)?;
+  }
+  zero_blocks(file, inner_start, inner_end - inner_start, align)
+}
+
+/// Write `length` zeros at `offset`, at most 1MiB at a time.
+pub fn write_zeros_aligned(
+  file: &fs::File,
+  offset: u64,
+  length: u64,
+  align: u64,
+) -> io::Result<()> {
+  let zeros = vec![0; length.min(1 << 20) as usize];
+  let mut written = 0;
+  while written < length {
+    let len = (length - written).min(zeros.len() as u64) as usize;
+    write_all_at_aligned(file, &zeros[..len], offset + written, align)?;
+    written += len as u64;
+  }
+  Ok(())
+}
+
+#[cfg(all(
+  feature = "sparse",
+  any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "macos",
+  )
+))]
+fn zero_blocks(
+  file: &fs::File,
+  offset: u64,
+  length: u64,
+  align: u64,
+) -> Result<(), RandomAccessError> {
+  crate::trim(file, offset, length, align)
+}
+
+#[cfg(not(all(
+  feature = "sparse",
+  any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "macos",
+  )
+)))]
+fn zero_blocks(
+  file: &fs::File,
+  offset: u64,
+  length: u64,
+  align: u64,
+) -> Result<(), RandomAccessError> {
+  // Without hole punching, write zeros in chunks of at most 1MiB.
+  let chunk = round_up(1 << 20, align).min(length);
+  let zeros = AlignedBuffer::new(chunk as usize, align);
+  let mut written = 0;
+  while written < length {
+    let len = (length - written).min(chunk) as usize;
+    write_all_at(file, &zeros[..len], offset + written)?;
+    written += len as u64;
+  }
+  Ok(())
+}
+
+/// Read whole blocks into `buffer` from `offset` until it's full or the end
+/// of the file is reached, returning how many bytes were read.
+fn read_blocks(
+  file: &fs::File,
+  buffer: &mut [u8],
+  offset: u64,
+  align: u64,
+) -> io::Result<usize> {
+  let mut filled = 0;
+  while filled < buffer.len() {
+    match read_at(file, &mut buffer[filled..], offset + filled as u64) {
+      Ok(0) => break,
+      Ok(n) => {
+        filled += n;
+        // Anything but whole blocks means the end of the file, and reading
+        // on from an unaligned offset would fail anyway.
+        if n as u64 % align != 0 {
+          break;
+        }
+      }
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(filled)
+}
+
+fn round_down(value: u64, align: u64) -> u64 {
+  value - value % align
+}
+
+fn round_up(value: u64, align: u64) -> u64 {
+  round_down(value + align - 1, align)
+}
+
+/// Zeroed buffer whose start is aligned.
+struct AlignedBuffer {
+  bytes: Vec<u8>,
+  start: usize,
+  len: usize,
+}
+
+impl AlignedBuffer {
+  fn new(len: usize, align: u64) -> Self {
+    let align = align as usize;
+    let bytes = vec![0; len + align];
+    let start = bytes.as_ptr().align_offset(align);
+    Self { bytes, start, len }
+  }
+}
+
+impl Deref for AlignedBuffer {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    &self.bytes[self.start..self.start + self.len]
+  }
+}
+
+impl DerefMut for AlignedBuffer {
+  fn deref_mut(&mut self) -> &mut [u8] {
+    &mut self.bytes[self.start..self.start + self.len]
+  }
+}
@@ -0,0 +1,101 @@
+use crate::runtime;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Writes still to be done on drop, see [crate::Builder::write_buffer].
+pub(crate) type Pending = Box<dyn FnOnce(&fs::File) -> io::Result<()> + Send>;
+
+/// What to do about unsynced changes when storage is dropped without being
+/// closed, see [crate::Builder::drop_strategy].
+///
+/// This only matters with a [crate::SyncPolicy] that doesn't sync every
+/// change right away, or with [crate::Builder::write_buffer]: otherwise
+/// there is never anything left to sync on drop. Buffered writes are always
+/// written, the strategy only decides how, and whether they're synced.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DropStrategy {
+  /// Sync in the destructor, which blocks the dropping thread, and any tasks
+  /// waiting to run on it, for as long as the disk takes.
+  #[default]
+  Block,
+  /// Sync in a detached task on the runtime's blocking thread pool. The
+  /// dropping thread doesn't block, but failures can only be logged, and the
+  /// sync may never run if the runtime shuts down first. Falls back to
+  /// [DropStrategy::Block] when there's no blocking thread pool to use, see
+  /// the runtime features in the crate docs.
+  Spawn,
+  /// Don't sync, log a warning about the unsynced changes instead. Buffered
+  /// writes are written in the destructor.
+  Warn,
+  /// Don't sync. Panic about the unsynced changes in debug builds, log a
+  /// warning in release builds. Use this to find the places that forget to
+  /// close storage. Buffered writes are written in the destructor first.
+  DebugPanic,
+}
+
+impl DropStrategy {
+  /// Deal with `file` at `path` having unsynced changes, and maybe `pending`
+  /// writes, on drop.
+  pub(crate) fn on_unsynced_drop(
+    self,
+    file: Arc<fs::File>,
+    path: &Path,
+    pending: Option<Pending>,
+  ) {
+    match self {
+      Self::Block => {
+        write_or_warn(&file, path, pending);
+        sync_or_warn(&file, path);
+      }
+      Self::Spawn => {
+        let path = path.to_path_buf();
+        runtime::spawn_blocking_detached(move || {
+          write_or_warn(&file, &path, pending);
+          sync_or_warn(&file, &path);
+        });
+      }
+      Self::Warn => {
+        write_or_warn(&file, path, pending);
+        warn_unsynced(path);
+      }
+      Self::DebugPanic => {
+        write_or_warn(&file, path, pending);
+        // Panicking while already unwinding would abort.
+        if cfg!(debug_assertions) && !std::thread::panicking() {
+          panic!(
+            "Storage at {} dropped with unsynced changes",
+            path.display()
+          );
+        }
+        warn_unsynced(path);
+      }
+    }
+  }
+}
+
+fn write_or_warn(file: &fs::File, path: &Path, pending: Option<Pending>) {
+  if let Some(pending) = pending {
+    if let Err(err) = pending(file) {
+      log::warn!(
+        "Failed to write buffered writes to {} on drop: {}",
+        path.display(),
+        err
+      );
+    }
+  }
+}
+
+fn sync_or_warn(file: &fs::File, path: &Path) {
+  if let Err(err) = file.sync_all() {
+    log::warn!("Failed to sync {} on drop: {}", path.display(), err);
+  }
+}
+
+fn warn_unsynced(path: &Path) {
+  log::warn!(
+    "Storage at {} dropped with unsynced changes, close it or call sync_all() before dropping",
+    path.display()
+  );
+}
@@ -0,0 +1,96 @@
+use random_access_storage::RandomAccessError;
+use std::io;
+use std::path::PathBuf;
+
+/// Errors specific to [crate::RandomAccessDisk].
+///
+/// The [random_access_storage::RandomAccess] trait can only return
+/// [RandomAccessError], so these are returned wrapped as the `source` of a
+/// [RandomAccessError::IO]. Use [DiskError::from_random_access_error] to get
+/// them back out.
+#[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
+pub enum DiskError {
+  /// The file on disk ended before a read within the known length could be
+  /// filled, e.g. because it was truncated by someone else.
+  #[error(
+    "Short read at offset {offset}: expected {expected} bytes, file on disk ended after {actual}"
+  )]
+  ShortRead {
+    /// Offset of the read.
+    offset: u64,
+    /// Number of bytes requested.
+    expected: u64,
+    /// Number of bytes the file on disk actually had.
+    actual: u64,
+  },
+  /// Tried to change storage that was opened with
+  /// [crate::Builder::read_only].
+  #[error("Storage was opened read-only")]
+  ReadOnly,
+  /// There was no file to open, see [crate::OpenPolicy].
+  #[error("No file at {}", .path.display())]
+  NotFound {
+    /// Path of the missing file.
+    path: PathBuf,
+  },
+  /// There was a file already, see [crate::OpenPolicy::MustBeNew].
+  #[error("File already exists at {}", .path.display())]
+  AlreadyExists {
+    /// Path of the existing file.
+    path: PathBuf,
+  },
+  /// The storage was closed with [crate::RandomAccessDisk::close].
+  #[error("Storage is closed")]
+  Closed,
+  /// Someone else holds a conflicting lock on the file, see
+  /// [crate::Builder::lock].
+  #[error("File at {} is locked by another process", .path.display())]
+  Locked {
+    /// Path of the locked file.
+    path: PathBuf,
+  },
+  /// The file was changed by someone else since it was last looked at, see
+  /// [crate::Builder::revalidate] and [crate::RandomAccessDisk::refresh].
+  #[error("File at {} was modified externally", .path.display())]
+  ModifiedExternally {
+    /// Path of the modified file.
+    path: PathBuf,
+  },
+  /// Options given to [crate::Builder] can't be used together.
+  #[error("Incompatible options: {0}")]
+  IncompatibleOptions(&'static str),
+}
+
+impl DiskError {
+  /// Get the [DiskError] wrapped in `err`, if there is one.
+  pub fn from_random_access_error(err: &RandomAccessError) -> Option<&Self> {
+    match err {
+      RandomAccessError::IO { source, .. } => source.get_ref()?.downcast_ref(),
+      _ => None,
+    }
+  }
+
+  fn kind(&self) -> io::ErrorKind {
+    match self {
+      Self::ShortRead { .. } => io::ErrorKind::UnexpectedEof,
+      Self::ReadOnly => io::ErrorKind::PermissionDenied,
+      Self::NotFound { .. } => io::ErrorKind::NotFound,
+      Self::AlreadyExists { .. } => io::ErrorKind::AlreadyExists,
+      Self::Closed => io::ErrorKind::NotConnected,
+      Self::Locked { .. } => io::ErrorKind::WouldBlock,
+      Self::ModifiedExternally { .. } => io::ErrorKind::Other,
+      Self::IncompatibleOptions(_) => io::ErrorKind::InvalidInput,
+    }
+  }
+}
+
+impl From<DiskError> for RandomAccessError {
+  fn from(err: DiskError) -> Self {
+    Self::IO {
+      return_code: None,
+      context: Some(err.to_string()),
+      source: io::Error::new(err.kind(), err),
+    }
+  }
+}
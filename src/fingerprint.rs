@@ -0,0 +1,81 @@
+use crate::DiskError;
+use random_access_storage::RandomAccessError;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// What a file looked like when last looked at, to tell whether someone else
+/// has changed it since, see [crate::Builder::revalidate].
+#[derive(Debug, Clone)]
+pub struct Fingerprint {
+  modified: Option<SystemTime>,
+  // How many changes of our own had been made by then, which change the
+  // modification time too.
+  changes: u64,
+}
+
+impl Fingerprint {
+  /// Look at `file`, opened from `path`, after `changes` changes of our own.
+  /// Returns its fingerprint and length, or fails with
+  /// [DiskError::ModifiedExternally] if `path` is now another file or none.
+  pub fn take(
+    file: &fs::File,
+    path: &Path,
+    changes: u64,
+  ) -> Result<(Self, u64), RandomAccessError> {
+    let metadata = file.metadata()?;
+    match fs::metadata(path) {
+      Ok(at_path) if id(&at_path) == id(&metadata) => {}
+      Err(err) if err.kind() != io::ErrorKind::NotFound => {
+        return Err(err.into())
+      }
+      _ => return Err(modified_externally(path)),
+    }
+    let fingerprint = Self {
+      modified: metadata.modified().ok(),
+      changes,
+    };
+    Ok((fingerprint, metadata.len()))
+  }
+
+  /// Check that `file` is as we left it: the same file at `path`, `length`
+  /// bytes long, and not modified since unless by `changes` of our own.
+  /// Returns the new fingerprint, or fails with
+  /// [DiskError::ModifiedExternally].
+  pub fn check(
+    &self,
+    file: &fs::File,
+    path: &Path,
+    length: u64,
+    changes: u64,
+  ) -> Result<Self, RandomAccessError> {
+    let (fingerprint, actual) = Self::take(file, path, changes)?;
+    if actual != length
+      || (changes == self.changes && fingerprint.modified != self.modified)
+    {
+      return Err(modified_externally(path));
+    }
+    Ok(fingerprint)
+  }
+}
+
+fn modified_externally(path: &Path) -> RandomAccessError {
+  DiskError::ModifiedExternally {
+    path: path.to_path_buf(),
+  }
+  .into()
+}
+
+#[cfg(unix)]
+fn id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+  use std::os::unix::fs::MetadataExt;
+  Some((metadata.dev(), metadata.ino()))
+}
+
+/// Files can't be told apart without unstable APIs elsewhere, so only their
+/// length and modification time count.
+#[cfg(not(unix))]
+fn id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+  None
+}
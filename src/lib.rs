@@ -19,11 +19,37 @@
 //!
 //! ### `async-std` (default)
 //!
-//! Use the async-std runtime, on by default. Either this or `tokio` is mandatory.
+//! Run blocking file operations on the async-std blocking thread pool, on by
+//! default.
 //!
 //! ### `tokio`
 //!
-//! Use the tokio runtime. Either this or `async_std` is mandatory.
+//! Run blocking file operations on the tokio blocking thread pool when
+//! called within a tokio runtime.
+//!
+//! ### `smol`
+//!
+//! Run blocking file operations on the thread pool of the
+//! [blocking](https://docs.rs/blocking) crate, which smol uses.
+//!
+//! Any of the runtime features can be enabled together, in which case tokio
+//! is used within a tokio runtime, and otherwise async-std, and then smol.
+//! With none of them, file operations block the calling task.
+//!
+//! ### `io-uring`
+//!
+//! On Linux, run the reads, writes, syncs and hole punches of
+//! [RandomAccessDisk] through [io_uring](https://kernel.dk/io_uring.pdf)
+//! instead of the runtime's blocking thread pool, saving a thread hop per
+//! operation. Falls back to the thread pool if the kernel doesn't support
+//! io_uring. Does nothing on other platforms.
+//!
+//! ### `mmap`
+//!
+//! Add [RandomAccessMmap], which reads and writes through a memory mapping of
+//! the file instead.
+//!
+//! **NB**: If this is on, `unsafe` code is used to map files!
 //!
 //! ## Examples
 //!
@@ -34,7 +60,7 @@
 //! # tokio_test::block_on(async {
 //! # example().await;
 //! # });
-//! # #[cfg(feature = "async-std")]
+//! # #[cfg(not(feature = "tokio"))]
 //! # async_std::task::block_on(async {
 //! # example().await;
 //! # });
@@ -59,6 +85,8 @@
 //! # }
 //! ```
 //!
+//! Without an async runtime, use [blocking::RandomAccessDisk] instead.
+//!
 //! In order to get benefits from the swappable interface, you will
 //! in most cases want to use generic functions for storage manipulation:
 //!
@@ -67,7 +95,7 @@
 //! # tokio_test::block_on(async {
 //! # example().await;
 //! # });
-//! # #[cfg(feature = "async-std")]
+//! # #[cfg(not(feature = "tokio"))]
 //! # async_std::task::block_on(async {
 //! # example().await;
 //! # });
@@ -97,31 +125,49 @@
 //! }
 //! # }
 
-#[cfg(not(any(feature = "async-std", feature = "tokio")))]
-compile_error!(
-  "Either feature `random-access-disk/async-std` or `random-access-disk/tokio` must be enabled."
-);
-
-#[cfg(all(feature = "async-std", feature = "tokio"))]
-compile_error!("features `random-access-disk/async-std` and `random-access-disk/tokio` are mutually exclusive");
-
-#[cfg(feature = "async-std")]
-use async_std::{
-  fs::{self, OpenOptions},
-  io::prelude::{SeekExt, WriteExt},
-  io::{ReadExt, SeekFrom},
-};
 use random_access_storage::{RandomAccess, RandomAccessError};
-use std::ops::Drop;
+use std::fs::{self, OpenOptions};
+use std::ops::{Drop, Range};
 use std::path;
+use std::time::Duration;
 
-#[cfg(feature = "tokio")]
-use std::io::SeekFrom;
-#[cfg(feature = "tokio")]
-use tokio::{
-  fs::{self, OpenOptions},
-  io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
-};
+mod backend;
+mod batch;
+pub mod blocking;
+mod direct;
+mod drop_strategy;
+mod error;
+mod fingerprint;
+mod lock;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod positional;
+mod range_lock;
+mod read_cache;
+mod runtime;
+mod shared;
+mod sync_policy;
+mod trim_mode;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+mod write_buffer;
+
+use backend::Backend;
+pub use batch::RandomAccessBatch;
+pub use drop_strategy::DropStrategy;
+pub use error::DiskError;
+use fingerprint::Fingerprint;
+pub use lock::LockMode;
+#[cfg(feature = "mmap")]
+pub use mmap::RandomAccessMmap;
+use read_cache::ReadCache;
+pub use read_cache::{EvictionPolicy, ReadCacheStats};
+use runtime::unblock;
+pub use shared::SharedRandomAccessDisk;
+pub use sync_policy::SyncPolicy;
+use sync_policy::{SyncKind, SyncState};
+pub use trim_mode::TrimMode;
+use write_buffer::WriteBuffer;
 
 #[cfg(all(
   feature = "sparse",
@@ -142,12 +188,18 @@ mod unix;
     target_os = "macos",
   )
 ))]
-use unix::{get_length_and_block_size, set_sparse, trim};
+use unix::{
+  allocated_size, data_ranges, get_length_and_block_size, preallocate,
+  set_sparse, trim, zero_range,
+};
 
 #[cfg(all(feature = "sparse", windows))]
 mod windows;
 #[cfg(all(feature = "sparse", windows))]
-use windows::{get_length_and_block_size, set_sparse, trim};
+use windows::{
+  allocated_size, data_ranges, get_length_and_block_size, preallocate,
+  set_sparse, trim, zero_range,
+};
 
 #[cfg(not(all(
   feature = "sparse",
@@ -171,19 +223,33 @@ mod default;
     windows,
   )
 )))]
-use default::{get_length_and_block_size, set_sparse, trim};
+use default::{
+  allocated_size, data_ranges, get_length_and_block_size, preallocate,
+  set_sparse, trim, zero_range,
+};
 
 /// Main constructor.
 #[derive(Debug)]
 pub struct RandomAccessDisk {
-  #[allow(dead_code)]
   filename: path::PathBuf,
-  file: Option<fs::File>,
+  backend: Option<Backend>,
   length: u64,
   block_size: u64,
-  auto_sync: bool,
+  sync: SyncState,
+  read_only: bool,
+  locked: bool,
+  drop_strategy: DropStrategy,
+  // Reused by read_into.
+  scratch: Vec<u8>,
+  write_buffer: Option<WriteBuffer>,
+  read_cache: Option<ReadCache>,
+  // The file as last looked at, with Builder::revalidate.
+  fingerprint: Option<Fingerprint>,
 }
 
+/// Largest scratch buffer kept around between calls to read_into.
+const MAX_SCRATCH: usize = 1 << 20;
+
 impl RandomAccessDisk {
   /// Create a new (auto-sync) instance to storage at `filename`.
   #[allow(clippy::new_ret_no_self)]
@@ -197,6 +263,347 @@ impl RandomAccessDisk {
   pub fn builder(filename: impl AsRef<path::Path>) -> Builder {
     Builder::new(filename)
   }
+
+  /// Sync all changes to disk, release the lock taken with
+  /// [Builder::lock] and close the file. Unlike dropping, this reports
+  /// errors. Afterwards all other methods fail with [DiskError::Closed], and
+  /// closing again does nothing.
+  pub async fn close(&mut self) -> Result<(), RandomAccessError> {
+    if self.backend.is_none() {
+      return Ok(());
+    }
+    self.flush().await?;
+    let Some(backend) = self.backend.take() else {
+      return Ok(());
+    };
+    if self.sync.is_dirty() {
+      let mark = self.sync.mark();
+      backend.sync(SyncKind::All).await?;
+      self.sync.synced(mark);
+    }
+    if self.locked {
+      backend.unblock(lock::unlock).await?;
+    }
+    Ok(())
+  }
+
+  /// Ranges within `length` bytes at `offset` that hold data, in order, as
+  /// opposed to holes left by [RandomAccess::del] and by writing past the
+  /// end. Holes read back as zeros, but take no space on disk. File systems
+  /// without holes, and builds without the `sparse` feature, report
+  /// everything as data. Buffered writes are written out first, see
+  /// [Builder::write_buffer].
+  pub async fn data_ranges(
+    &mut self,
+    offset: u64,
+    length: u64,
+  ) -> Result<Vec<Range<u64>>, RandomAccessError> {
+    self.flush().await?;
+    let backend = self.backend()?;
+    let end = offset.saturating_add(length).min(self.length);
+    if offset >= end {
+      return Ok(vec![]);
+    }
+    backend
+      .unblock(move |file| data_ranges(file, offset, end - offset))
+      .await
+  }
+
+  /// Ranges within `length` bytes at `offset` that are holes, in order, the
+  /// counterpart of [RandomAccessDisk::data_ranges].
+  pub async fn holes(
+    &mut self,
+    offset: u64,
+    length: u64,
+  ) -> Result<Vec<Range<u64>>, RandomAccessError> {
+    let end = offset.saturating_add(length).min(self.length);
+    let mut holes = vec![];
+    let mut position = offset;
+    for range in self.data_ranges(offset, length).await? {
+      if range.start > position {
+        holes.push(position..range.start);
+      }
+      position = range.end;
+    }
+    if position < end {
+      holes.push(position..end);
+    }
+    Ok(holes)
+  }
+
+  /// Zero out `length` bytes at `offset`. Unlike [RandomAccess::del], this
+  /// never changes the length, and the range stays allocated on disk, so
+  /// rewriting it later doesn't run out of space. Uses
+  /// `FALLOC_FL_ZERO_RANGE` on Linux where the file system supports it, and
+  /// writes zeros elsewhere. Fails with [RandomAccessError::OutOfBounds] if
+  /// the range reaches past the end.
+  pub async fn zero(
+    &mut self,
+    offset: u64,
+    length: u64,
+  ) -> Result<(), RandomAccessError> {
+    self.check_writable()?;
+    self.check_bounds(offset, length)?;
+    if length == 0 {
+      return Ok(());
+    }
+    // Buffered writes to the range would otherwise land on top of the zeros
+    // later.
+    self.flush().await?;
+    let backend = self.backend()?;
+    let sync = self.sync.record();
+    backend
+      .zero(offset, length, sync.map(|(kind, _)| kind))
+      .await?;
+    self.synced(sync);
+    if let Some(cache) = &mut self.read_cache {
+      cache.zero(offset, length);
+    }
+    Ok(())
+  }
+
+  /// Reserve disk space for `length` bytes at `offset`, without changing the
+  /// length, so that later writes there don't run out of space and the file
+  /// is less fragmented. Fails right away if there isn't enough space. Not
+  /// supported everywhere, in which case this does nothing, see
+  /// [Builder::preallocate].
+  pub async fn preallocate(
+    &mut self,
+    offset: u64,
+    length: u64,
+  ) -> Result<(), RandomAccessError> {
+    self.check_writable()?;
+    let backend = self.backend()?;
+    if length == 0 {
+      return Ok(());
+    }
+    let sync = self.sync.record();
+    backend.preallocate(offset, length).await?;
+    if let Some((kind, _)) = sync {
+      backend.sync(kind).await?;
+    }
+    self.synced(sync);
+    Ok(())
+  }
+
+  /// Space the file takes on disk, which can be less than its length where
+  /// [RandomAccess::del] punched holes or it was written past the end, see
+  /// also [RandomAccessDisk::holes]. File systems can also take more, e.g.
+  /// for the rest of the last block. Builds without the `sparse` feature
+  /// report the file's length. Buffered writes are written out first, see
+  /// [Builder::write_buffer].
+  pub async fn allocated_size(&mut self) -> Result<u64, RandomAccessError> {
+    self.flush().await?;
+    let backend = self.backend()?;
+    let filename = self.filename.clone();
+    backend
+      .unblock(move |file| allocated_size(file, &filename))
+      .await
+  }
+
+  /// Get the length from the file on disk again, for when someone else may
+  /// have changed it, and drop what's in the read cache. Buffered writes are
+  /// written out first. Fails with [DiskError::ModifiedExternally] if the
+  /// file at the path was deleted or replaced since it was opened, as the
+  /// opened one is still used. See also [Builder::revalidate].
+  pub async fn refresh(&mut self) -> Result<(), RandomAccessError> {
+    self.flush().await?;
+    let backend = self.backend()?;
+    let filename = self.filename.clone();
+    let changes = self.sync.changes();
+    let (fingerprint, length) = backend
+      .unblock(move |file| Fingerprint::take(file, &filename, changes))
+      .await?;
+    self.length = length;
+    if let Some(buffer) = &mut self.write_buffer {
+      buffer.set_file_length(length);
+    }
+    if let Some(cache) = &mut self.read_cache {
+      cache.clear();
+    }
+    if self.fingerprint.is_some() {
+      self.fingerprint = Some(fingerprint);
+    }
+    Ok(())
+  }
+
+  /// Hit and miss counters of the read cache, if there is one, see
+  /// [Builder::read_cache].
+  pub fn read_cache_stats(&self) -> Option<ReadCacheStats> {
+    self.read_cache.as_ref().map(ReadCache::stats)
+  }
+
+  fn backend(&self) -> Result<Backend, RandomAccessError> {
+    self.backend.clone().ok_or_else(|| DiskError::Closed.into())
+  }
+
+  /// The write buffer, if there's anything in it to flush.
+  fn dirty_write_buffer(&self) -> Option<&WriteBuffer> {
+    let length = self.length;
+    self
+      .write_buffer
+      .as_ref()
+      .filter(|buffer| !buffer.is_clean(length))
+  }
+
+  /// Fail with [DiskError::ModifiedExternally] if the file isn't as we left
+  /// it, with [Builder::revalidate].
+  async fn revalidate(&mut self) -> Result<(), RandomAccessError> {
+    let Some(fingerprint) = self.fingerprint.clone() else {
+      return Ok(());
+    };
+    let backend = self.backend()?;
+    let filename = self.filename.clone();
+    let length = self
+      .write_buffer
+      .as_ref()
+      .map_or(self.length, WriteBuffer::file_length);
+    let changes = self.sync.changes();
+    let fingerprint = backend
+      .unblock(move |file| fingerprint.check(file, &filename, length, changes))
+      .await?;
+    self.fingerprint = Some(fingerprint);
+    Ok(())
+  }
+
+  /// Write out what's in the write buffer, syncing as the [SyncPolicy]
+  /// says. If this fails, the writes stay buffered, to be written out on the
+  /// next try.
+  async fn flush(&mut self) -> Result<(), RandomAccessError> {
+    if self.dirty_write_buffer().is_none() {
+      return Ok(());
+    }
+    let backend = self.backend()?;
+    let length = self.length;
+    let Some(buffer) = self.write_buffer.as_mut() else {
+      return Ok(());
+    };
+    let min_length = if buffer.file_length() < length {
+      length
+    } else {
+      0
+    };
+    let runs = buffer.runs();
+    let sync = self.sync.record();
+    backend
+      .write_batch(runs, min_length, sync.map(|(kind, _)| kind))
+      .await?;
+    if let Some(buffer) = self.write_buffer.as_mut() {
+      buffer.flushed(length);
+    }
+    self.synced(sync);
+    Ok(())
+  }
+
+  /// Read `length` bytes at `offset` through the read cache, reading the
+  /// blocks that aren't cached with one read and caching them.
+  async fn read_cached(
+    &mut self,
+    offset: u64,
+    length: u64,
+  ) -> Result<Vec<u8>, RandomAccessError> {
+    let mut data = vec![0; length as usize];
+    let Some(cache) = self.read_cache.as_mut() else {
+      return self.read_to(offset, data).await;
+    };
+    if length == 0 {
+      return Ok(data);
+    }
+    let end = offset + length;
+    let block_size = cache.block_size();
+    // First and last block that aren't cached.
+    let mut missing: Option<(u64, u64)> = None;
+    for block in offset / block_size..=(end - 1) / block_size {
+      let block_start = block * block_size;
+      let from = offset.max(block_start);
+      let to = end.min(block_start + block_size);
+      match cache.get(block, (to - block_start) as usize) {
+        Some(cached) => data[(from - offset) as usize..(to - offset) as usize]
+          .copy_from_slice(&cached[(from - block_start) as usize..]),
+        None => {
+          missing = Some((missing.map_or(block, |(first, _)| first), block))
+        }
+      }
+    }
+
+    if let Some((first, last)) = missing {
+      let start = first * block_size;
+      let span_end = ((last + 1) * block_size).min(self.length);
+      let read = self
+        .read_to(start, vec![0; (span_end - start) as usize])
+        .await?;
+      let from = offset.max(start);
+      let to = end.min(span_end);
+      data[(from - offset) as usize..(to - offset) as usize]
+        .copy_from_slice(&read[(from - start) as usize..(to - start) as usize]);
+      if let Some(cache) = self.read_cache.as_mut() {
+        for (i, block) in read.chunks(block_size as usize).enumerate() {
+          cache.insert(first + i as u64, block.to_vec());
+        }
+      }
+    }
+    Ok(data)
+  }
+
+  /// Fill `buffer` with what's at `offset`, taking buffered writes into
+  /// account.
+  async fn read_to(
+    &self,
+    offset: u64,
+    mut buffer: Vec<u8>,
+  ) -> Result<Vec<u8>, RandomAccessError> {
+    let backend = self.backend()?;
+    let Some(write_buffer) = self.dirty_write_buffer() else {
+      return backend.read_to(offset, buffer).await;
+    };
+    let length = buffer.len() as u64;
+    if !write_buffer.covers(offset, length) {
+      // Only what the file on disk already has can be read from it, the rest
+      // is zeros until flushed.
+      let disk_end = (offset + length).min(write_buffer.file_length());
+      if disk_end == offset + length {
+        buffer = backend.read_to(offset, buffer).await?;
+      } else {
+        buffer.fill(0);
+        if disk_end > offset {
+          let disk = backend.read_at(offset, disk_end - offset).await?;
+          buffer[..disk.len()].copy_from_slice(&disk);
+        }
+      }
+    }
+    write_buffer.overlay(offset, &mut buffer);
+    Ok(buffer)
+  }
+
+  /// Record a sync done right after a change as decided by
+  /// [SyncState::record].
+  fn synced(&mut self, sync: Option<(SyncKind, u64)>) {
+    if let Some((_, mark)) = sync {
+      self.sync.synced(mark);
+    }
+  }
+
+  fn check_writable(&self) -> Result<(), RandomAccessError> {
+    if self.read_only {
+      return Err(DiskError::ReadOnly.into());
+    }
+    Ok(())
+  }
+
+  fn check_bounds(
+    &self,
+    offset: u64,
+    length: u64,
+  ) -> Result<(), RandomAccessError> {
+    if offset + length > self.length {
+      return Err(RandomAccessError::OutOfBounds {
+        offset,
+        end: Some(offset + length),
+        length: self.length,
+      });
+    }
+    Ok(())
+  }
 }
 
 #[async_trait::async_trait]
@@ -206,15 +613,39 @@ impl RandomAccess for RandomAccessDisk {
     offset: u64,
     data: &[u8],
   ) -> Result<(), RandomAccessError> {
-    let file = self.file.as_mut().expect("self.file was None.");
-    file.seek(SeekFrom::Start(offset)).await?;
-    file.write_all(data).await?;
-    if self.auto_sync {
-      file.sync_all().await?;
+    self.check_writable()?;
+    let backend = self.backend()?;
+    let new_len = offset + (data.len() as u64);
+    if let Some(buffer) = &mut self.write_buffer {
+      if let Some(cache) = &mut self.read_cache {
+        cache.write(offset, data);
+      }
+      buffer.insert(offset, data);
+      let full = buffer.is_full();
+      if new_len > self.length {
+        self.length = new_len;
+      }
+      if full {
+        self.flush().await?;
+      }
+      return Ok(());
+    }
+    // An empty write past the end still grows the length, so make sure the
+    // file on disk grows with it. Reads rely on the two matching.
+    let extend = data.is_empty() && new_len > self.length;
+    let sync = self.sync.record();
+    let kind = sync.map(|(kind, _)| kind);
+    if extend {
+      backend.set_len(new_len, kind).await?;
+    } else {
+      backend.write_at(offset, data.to_vec(), kind).await?;
+    }
+    self.synced(sync);
+    if let Some(cache) = &mut self.read_cache {
+      cache.write(offset, data);
     }
 
     // We've changed the length of our file.
-    let new_len = offset + (data.len() as u64);
     if new_len > self.length {
       self.length = new_len;
     }
@@ -222,32 +653,23 @@ impl RandomAccess for RandomAccessDisk {
     Ok(())
   }
 
-  // NOTE(yw): disabling clippy here because we files on disk might be sparse,
-  // and sometimes you might want to read a bit of memory to check if it's
-  // formatted or not. Returning zero'd out memory seems like an OK thing to do.
-  // We should probably come back to this at a future point, and determine
-  // whether it's okay to return a fully zero'd out slice. It's a bit weird,
-  // because we're replacing empty data with actual zeroes - which does not
-  // reflect the state of the world.
-  // #[cfg_attr(test, allow(unused_io_amount))]
+  // Files on disk might be sparse, in which case the holes read back as
+  // zeros. The file on disk always has at least the cached length, so
+  // coming up short means someone else truncated it under us.
   async fn read(
     &mut self,
     offset: u64,
     length: u64,
   ) -> Result<Vec<u8>, RandomAccessError> {
-    if offset + length > self.length {
-      return Err(RandomAccessError::OutOfBounds {
-        offset,
-        end: Some(offset + length),
-        length: self.length,
-      });
+    self.revalidate().await?;
+    self.check_bounds(offset, length)?;
+    if self.read_cache.is_some() {
+      return self.read_cached(offset, length).await;
     }
-
-    let file = self.file.as_mut().expect("self.file was None.");
-    let mut buffer = vec![0; length as usize];
-    file.seek(SeekFrom::Start(offset)).await?;
-    let _bytes_read = file.read(&mut buffer[..]).await?;
-    Ok(buffer)
+    if self.dirty_write_buffer().is_some() {
+      return self.read_to(offset, vec![0; length as usize]).await;
+    }
+    self.backend()?.read_at(offset, length).await
   }
 
   async fn del(
@@ -255,6 +677,7 @@ impl RandomAccess for RandomAccessDisk {
     offset: u64,
     length: u64,
   ) -> Result<(), RandomAccessError> {
+    self.check_writable()?;
     if offset > self.length {
       return Err(RandomAccessError::OutOfBounds {
         offset,
@@ -273,74 +696,233 @@ impl RandomAccess for RandomAccessDisk {
       return self.truncate(offset).await;
     }
 
-    let file = self.file.as_mut().expect("self.file was None.");
-    trim(file, offset, length, self.block_size).await?;
-    if self.auto_sync {
-      file.sync_all().await?;
+    // Buffered writes to the range would otherwise land on top of the hole
+    // later.
+    self.flush().await?;
+    let backend = self.backend()?;
+    let sync = self.sync.record();
+    backend
+      .trim(offset, length, self.block_size, sync.map(|(kind, _)| kind))
+      .await?;
+    self.synced(sync);
+    if let Some(cache) = &mut self.read_cache {
+      cache.zero(offset, length);
     }
     Ok(())
   }
 
   async fn truncate(&mut self, length: u64) -> Result<(), RandomAccessError> {
-    let file = self.file.as_ref().expect("self.file was None.");
+    self.check_writable()?;
+    let backend = self.backend()?;
+    self.flush().await?;
     self.length = length;
-    file.set_len(self.length).await?;
-    if self.auto_sync {
-      file.sync_all().await?;
+    if let Some(cache) = &mut self.read_cache {
+      cache.truncate(length);
+    }
+    let sync = self.sync.record();
+    backend.set_len(length, sync.map(|(kind, _)| kind)).await?;
+    self.synced(sync);
+    if let Some(buffer) = &mut self.write_buffer {
+      buffer.set_file_length(length);
     }
     Ok(())
   }
 
   async fn len(&mut self) -> Result<u64, RandomAccessError> {
+    self.backend()?;
     Ok(self.length)
   }
 
   async fn is_empty(&mut self) -> Result<bool, RandomAccessError> {
+    self.backend()?;
     Ok(self.length == 0)
   }
 
   async fn sync_all(&mut self) -> Result<(), RandomAccessError> {
-    if !self.auto_sync {
-      let file = self.file.as_ref().expect("self.file was None.");
-      file.sync_all().await?;
+    let backend = self.backend()?;
+    self.flush().await?;
+    if self.sync.is_dirty() {
+      let mark = self.sync.mark();
+      backend.sync(SyncKind::All).await?;
+      self.sync.synced(mark);
     }
     Ok(())
   }
 }
 
+/// Writes adjacent ranges with one vectored write each, and syncs once per
+/// batch as far as the [SyncPolicy] is concerned.
+#[async_trait::async_trait]
+impl RandomAccessBatch for RandomAccessDisk {
+  async fn write_batch(
+    &mut self,
+    ops: &[(u64, &[u8])],
+  ) -> Result<(), RandomAccessError> {
+    self.check_writable()?;
+    let backend = self.backend()?;
+    if self.write_buffer.is_some() {
+      for (offset, data) in ops {
+        self.write(*offset, data).await?;
+      }
+      return Ok(());
+    }
+    if ops.is_empty() {
+      return Ok(());
+    }
+    let new_len = ops
+      .iter()
+      .map(|(offset, data)| offset + data.len() as u64)
+      .max()
+      .unwrap_or(0);
+    // Empty writes past the end grow the length too, see write.
+    let min_len = if new_len > self.length { new_len } else { 0 };
+    let runs = batch::plan(ops);
+    let sync = self.sync.record();
+    backend
+      .write_batch(runs, min_len, sync.map(|(kind, _)| kind))
+      .await?;
+    self.synced(sync);
+    if let Some(cache) = &mut self.read_cache {
+      for (offset, data) in ops {
+        cache.write(*offset, data);
+      }
+    }
+
+    if new_len > self.length {
+      self.length = new_len;
+    }
+    Ok(())
+  }
+
+  /// Reads through a scratch buffer that is reused between calls, as the
+  /// read itself runs elsewhere.
+  async fn read_into(
+    &mut self,
+    offset: u64,
+    buffer: &mut [u8],
+  ) -> Result<(), RandomAccessError> {
+    self.revalidate().await?;
+    self.check_bounds(offset, buffer.len() as u64)?;
+    if self.read_cache.is_some() {
+      let data = self.read_cached(offset, buffer.len() as u64).await?;
+      buffer.copy_from_slice(&data);
+      return Ok(());
+    }
+    let mut scratch = std::mem::take(&mut self.scratch);
+    scratch.resize(buffer.len(), 0);
+    let scratch = self.read_to(offset, scratch).await?;
+    buffer.copy_from_slice(&scratch);
+    if scratch.capacity() <= MAX_SCRATCH {
+      self.scratch = scratch;
+    }
+    Ok(())
+  }
+
+  /// Reads ranges that are close to each other with one vectored read.
+  async fn read_batch(
+    &mut self,
+    ranges: &[(u64, u64)],
+  ) -> Vec<Result<Vec<u8>, RandomAccessError>> {
+    if self.dirty_write_buffer().is_some()
+      || self.read_cache.is_some()
+      || self.fingerprint.is_some()
+    {
+      let mut results = Vec::with_capacity(ranges.len());
+      for &(offset, length) in ranges {
+        results.push(self.read(offset, length).await);
+      }
+      return results;
+    }
+    let backend = match self.backend() {
+      Ok(backend) => backend,
+      Err(_) => {
+        return ranges
+          .iter()
+          .map(|_| Err(DiskError::Closed.into()))
+          .collect()
+      }
+    };
+    let mut results: Vec<_> = ranges
+      .iter()
+      .map(|&(offset, length)| self.check_bounds(offset, length).err())
+      .collect();
+    let in_bounds: Vec<_> = ranges
+      .iter()
+      .zip(&results)
+      .filter(|(_, err)| err.is_none())
+      .map(|(&range, _)| range)
+      .collect();
+    let mut read = backend.read_batch(in_bounds).await.into_iter();
+    results
+      .iter_mut()
+      .map(|err| match err.take() {
+        Some(err) => Err(err),
+        None => read.next().unwrap(),
+      })
+      .collect()
+  }
+}
+
 impl Drop for RandomAccessDisk {
   fn drop(&mut self) {
-    // We need to flush the file on drop. Unfortunately, that is not possible to do in a
-    // non-blocking fashion, but our only other option here is losing data remaining in the
-    // write cache. Good task schedulers should be resilient to occasional blocking hiccups in
-    // file destructors so we don't expect this to be a common problem in practice.
-    // (from async_std::fs::File::drop)
-    #[cfg(feature = "async-std")]
-    if let Some(file) = &self.file {
-      let _ = async_std::task::block_on(file.sync_all());
-    }
-    // For tokio, the below errors with:
-    //
-    // "Cannot start a runtime from within a runtime. This happens because a function (like
-    // `block_on`) attempted to block the current thread while the thread is being used to
-    // drive asynchronous tasks."
-    //
-    // There doesn't seem to be an equivalent block_on version for tokio that actually works
-    // in a sync drop(), so for tokio, we'll need to wait for a real AsyncDrop to arrive.
-    //
-    // #[cfg(feature = "tokio")]
-    // if let Some(file) = &self.file {
-    //   tokio::runtime::Handle::current()
-    //     .block_on(file.sync_all())
-    //     .expect("Could not sync file changes on drop.");
-    // }
+    let Some(backend) = self.backend.take() else {
+      return;
+    };
+    let length = self.length;
+    let pending = match self.write_buffer.as_mut() {
+      Some(buffer) if !buffer.is_clean(length) => {
+        let min_length = if buffer.file_length() < length {
+          length
+        } else {
+          0
+        };
+        Some(backend.pending(buffer.take(length), min_length))
+      }
+      _ => None,
+    };
+    if pending.is_some() || self.sync.is_dirty() {
+      self.drop_strategy.on_unsynced_drop(
+        backend.file().clone(),
+        &self.filename,
+        pending,
+      );
+    }
   }
 }
 
+/// What [Builder] does depending on whether the file exists already.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OpenPolicy {
+  /// Open the file, creating it and its parent directories if missing.
+  #[default]
+  CreateIfMissing,
+  /// Open an existing file, fail with [DiskError::NotFound] if missing.
+  MustExist,
+  /// Create a new file and its parent directories, fail with
+  /// [DiskError::AlreadyExists] if the file exists.
+  MustBeNew,
+  /// Open an existing file and truncate it to zero length, fail with
+  /// [DiskError::NotFound] if missing.
+  TruncateExisting,
+}
+
 /// Builder for [RandomAccessDisk]
 pub struct Builder {
   filename: path::PathBuf,
-  auto_sync: bool,
+  sync_policy: SyncPolicy,
+  drop_strategy: DropStrategy,
+  read_only: bool,
+  open_policy: OpenPolicy,
+  lock_mode: LockMode,
+  lock_timeout: Option<Duration>,
+  direct_io: bool,
+  write_buffer: Option<usize>,
+  read_cache: Option<usize>,
+  read_cache_eviction: EvictionPolicy,
+  read_cache_block_size: Option<u64>,
+  preallocate: u64,
+  trim_mode: TrimMode,
+  revalidate: bool,
 }
 
 impl Builder {
@@ -348,41 +930,368 @@ impl Builder {
   pub fn new(filename: impl AsRef<path::Path>) -> Self {
     Self {
       filename: filename.as_ref().into(),
-      auto_sync: true,
+      sync_policy: SyncPolicy::default(),
+      drop_strategy: DropStrategy::default(),
+      read_only: false,
+      open_policy: OpenPolicy::default(),
+      lock_mode: LockMode::default(),
+      lock_timeout: None,
+      direct_io: false,
+      write_buffer: None,
+      read_cache: None,
+      read_cache_eviction: EvictionPolicy::default(),
+      read_cache_block_size: None,
+      preallocate: 0,
+      trim_mode: TrimMode::default(),
+      revalidate: false,
     }
   }
 
-  /// Set auto-sync
-  // NB: Because of no AsyncDrop, tokio can not ensure that changes are synced when dropped,
-  // see impl Drop above.
-  #[cfg(feature = "async-std")]
-  pub fn auto_sync(mut self, auto_sync: bool) -> Self {
-    self.auto_sync = auto_sync;
+  /// Set auto-sync. Shorthand for [SyncPolicy::Always] when true, and
+  /// [SyncPolicy::Manual] when false.
+  pub fn auto_sync(self, auto_sync: bool) -> Self {
+    self.sync_policy(if auto_sync {
+      SyncPolicy::Always
+    } else {
+      SyncPolicy::Manual
+    })
+  }
+
+  /// Set when changes are synced to disk. Changes that aren't synced yet
+  /// are synced on [RandomAccess::sync_all], [RandomAccessDisk::close] and
+  /// as set with [Builder::drop_strategy]. Defaults to
+  /// [SyncPolicy::Always].
+  pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+    self.sync_policy = sync_policy;
     self
   }
 
-  /// Build a [RandomAccessDisk] instance
-  pub async fn build(self) -> Result<RandomAccessDisk, RandomAccessError> {
-    if let Some(dirname) = self.filename.parent() {
-      mkdirp::mkdirp(dirname)?;
-    }
-    let mut file = OpenOptions::new()
-      .create(true)
-      .read(true)
-      .write(true)
-      .open(&self.filename)
-      .await?;
-    file.sync_all().await?;
+  /// Open the file without write access. The file and its directory are
+  /// never created, and [RandomAccess::write], [RandomAccess::del] and
+  /// [RandomAccess::truncate] fail with [DiskError::ReadOnly]. Defaults to
+  /// false.
+  pub fn read_only(mut self, read_only: bool) -> Self {
+    self.read_only = read_only;
+    self
+  }
+
+  /// Set what to do about unsynced changes when dropped without calling
+  /// [RandomAccessDisk::close]. Defaults to [DropStrategy::Block], which
+  /// blocks the dropping thread. With tokio, consider [DropStrategy::Spawn]
+  /// instead.
+  pub fn drop_strategy(mut self, drop_strategy: DropStrategy) -> Self {
+    self.drop_strategy = drop_strategy;
+    self
+  }
+
+  /// Set what to do depending on whether the file exists already, see
+  /// [OpenPolicy]. Defaults to [OpenPolicy::CreateIfMissing].
+  ///
+  /// With [Builder::read_only], the file is never created, and
+  /// [OpenPolicy::MustBeNew] and [OpenPolicy::TruncateExisting] fail with
+  /// [DiskError::IncompatibleOptions].
+  pub fn open_policy(mut self, open_policy: OpenPolicy) -> Self {
+    self.open_policy = open_policy;
+    self
+  }
+
+  /// Take an advisory lock on the file when opening, held until the storage
+  /// is closed or dropped. This keeps e.g. two processes from writing to the
+  /// same file. If someone else holds a conflicting lock, building fails
+  /// with [DiskError::Locked], see also [Builder::lock_timeout]. Defaults to
+  /// [LockMode::None].
+  pub fn lock(mut self, lock_mode: LockMode) -> Self {
+    self.lock_mode = lock_mode;
+    self
+  }
+
+  /// Wait up to `timeout` for a conflicting lock to be released before
+  /// failing, instead of failing right away.
+  pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+    self.lock_timeout = Some(timeout);
+    self
+  }
+
+  /// Bypass the page cache, opening the file with `O_DIRECT` on Linux and
+  /// FreeBSD, and `F_NOCACHE` on macOS. Reads and writes that aren't aligned
+  /// to the file system's block size read and write the whole blocks around
+  /// them, so they cost more than with the page cache. Not supported on
+  /// other platforms, where building fails with
+  /// [DiskError::IncompatibleOptions], and only for [Builder::build].
+  /// Defaults to false.
+  pub fn direct_io(mut self, direct_io: bool) -> Self {
+    self.direct_io = direct_io;
+    self
+  }
+
+  /// Keep writes in memory until about `bytes` of them have piled up, merging
+  /// overlapping and adjacent ones, instead of writing each one right away.
+  /// Reads see the buffered writes. The buffer is also written out on
+  /// [RandomAccess::del], [RandomAccess::truncate], [RandomAccess::sync_all]
+  /// and [RandomAccessDisk::close], and when dropped, see [DropStrategy].
+  /// The [SyncPolicy] then applies to writing out the buffer rather than to
+  /// each write. Only for [Builder::build]. Defaults to no buffering.
+  pub fn write_buffer(mut self, bytes: usize) -> Self {
+    self.write_buffer = Some(bytes);
+    self
+  }
+
+  /// Cache up to `bytes` of recently read blocks in memory, and read from
+  /// there instead of from the file. Writes, deletes and truncates update the
+  /// cache, but changes made to the file by others aren't noticed until
+  /// [RandomAccessDisk::refresh]. See
+  /// [RandomAccessDisk::read_cache_stats] for how well it works. Only for
+  /// [Builder::build]. Defaults to no caching.
+  pub fn read_cache(mut self, bytes: usize) -> Self {
+    self.read_cache = Some(bytes);
+    self
+  }
+
+  /// Set which block the read cache drops when full. Defaults to
+  /// [EvictionPolicy::Lru].
+  pub fn read_cache_eviction(mut self, policy: EvictionPolicy) -> Self {
+    self.read_cache_eviction = policy;
+    self
+  }
 
-    set_sparse(&mut file).await?;
+  /// Set the size of the blocks the read cache reads and keeps. Defaults to
+  /// the file system's block size.
+  pub fn read_cache_block_size(mut self, bytes: u64) -> Self {
+    self.read_cache_block_size = Some(bytes);
+    self
+  }
+
+  /// Reserve disk space for the first `bytes` of the file when opening, see
+  /// [RandomAccessDisk::preallocate]. With [Builder::read_only], building
+  /// fails with [DiskError::IncompatibleOptions]. Disk space is reserved
+  /// with `fallocate` on Linux and `F_PREALLOCATE` on macOS, which keep it
+  /// reserved, and on Windows by setting the allocation size, which lasts
+  /// until the file is closed. Other platforms, and builds without the
+  /// `sparse` feature, don't reserve anything. Defaults to 0.
+  pub fn preallocate(mut self, bytes: u64) -> Self {
+    self.preallocate = bytes;
+    self
+  }
+
+  /// Set what [RandomAccess::del] does when the file system can't punch
+  /// holes, see [TrimMode]. Defaults to [TrimMode::BestEffort].
+  pub fn trim_mode(mut self, trim_mode: TrimMode) -> Self {
+    self.trim_mode = trim_mode;
+    self
+  }
 
-    let (length, block_size) = get_length_and_block_size(&file).await?;
+  /// Check before each read that the file hasn't been changed by someone
+  /// else, failing with [DiskError::ModifiedExternally] if it has been
+  /// replaced, its length isn't what's expected, or it was modified since
+  /// last checked while this didn't change it. Changes of the same length
+  /// made right after one of ours may go unnoticed, as file systems only
+  /// keep modification times so precisely. Call [RandomAccessDisk::refresh]
+  /// to carry on with the file as it is. Costs two `stat` calls per read.
+  /// Replaced files are only noticed on Unix. Only for [Builder::build].
+  /// Defaults to false.
+  pub fn revalidate(mut self, revalidate: bool) -> Self {
+    self.revalidate = revalidate;
+    self
+  }
+
+  /// Build a [RandomAccessDisk] instance
+  pub async fn build(self) -> Result<RandomAccessDisk, RandomAccessError> {
+    let (file, length, block_size) = self.open().await?;
+    let direct = self.direct_io.then(|| direct::alignment(block_size));
+    let backend = Backend::new(file, direct, self.trim_mode);
+    let fingerprint = if self.revalidate {
+      let filename = self.filename.clone();
+      let (fingerprint, _) = backend
+        .unblock(move |file| Fingerprint::take(file, &filename, 0))
+        .await?;
+      Some(fingerprint)
+    } else {
+      None
+    };
     Ok(RandomAccessDisk {
       filename: self.filename,
-      file: Some(file),
+      backend: Some(backend),
       length,
-      auto_sync: self.auto_sync,
+      sync: SyncState::new(self.sync_policy),
+      read_only: self.read_only,
+      locked: self.lock_mode != LockMode::None,
+      drop_strategy: self.drop_strategy,
       block_size,
+      scratch: Vec::new(),
+      write_buffer: self
+        .write_buffer
+        .map(|capacity| WriteBuffer::new(capacity, length)),
+      read_cache: self.read_cache.map(|capacity| {
+        let cache_block_size = match self.read_cache_block_size {
+          Some(cache_block_size) => cache_block_size,
+          None if block_size > 0 => block_size,
+          None => 4096,
+        };
+        ReadCache::new(
+          capacity,
+          cache_block_size.max(1),
+          self.read_cache_eviction,
+        )
+      }),
+      fingerprint,
+    })
+  }
+
+  /// Build a [blocking::RandomAccessDisk] instance, without a runtime
+  pub fn build_blocking(
+    self,
+  ) -> Result<blocking::RandomAccessDisk, RandomAccessError> {
+    runtime::block_on(self.build()).map(blocking::RandomAccessDisk::new)
+  }
+
+  /// Build a [SharedRandomAccessDisk] instance
+  pub async fn build_shared(
+    self,
+  ) -> Result<SharedRandomAccessDisk, RandomAccessError> {
+    if self.direct_io
+      || self.write_buffer.is_some()
+      || self.read_cache.is_some()
+      || self.revalidate
+    {
+      return Err(
+        DiskError::IncompatibleOptions(
+          "direct I/O, write buffers, read caches and revalidation are not \
+           supported for shared storage",
+        )
+        .into(),
+      );
+    }
+    let (file, length, block_size) = self.open().await?;
+    Ok(SharedRandomAccessDisk::new(
+      self.filename,
+      file,
+      length,
+      block_size,
+      self.sync_policy,
+      self.drop_strategy,
+      self.read_only,
+      self.trim_mode,
+    ))
+  }
+
+  /// Build a [RandomAccessMmap] instance
+  #[cfg(feature = "mmap")]
+  pub async fn build_mmap(self) -> Result<RandomAccessMmap, RandomAccessError> {
+    if self.direct_io
+      || self.write_buffer.is_some()
+      || self.read_cache.is_some()
+      || self.revalidate
+    {
+      return Err(
+        DiskError::IncompatibleOptions(
+          "direct I/O, write buffers, read caches and revalidation can't be \
+           combined with memory mapping",
+        )
+        .into(),
+      );
+    }
+    let (file, length, block_size) = self.open().await?;
+    Ok(RandomAccessMmap::new(
+      self.filename,
+      file,
+      length,
+      block_size,
+      SyncState::new(self.sync_policy),
+      self.read_only,
+      self.lock_mode != LockMode::None,
+      self.drop_strategy,
+      self.trim_mode,
+    ))
+  }
+
+  /// Open the file, returning it with its length and block size.
+  async fn open(&self) -> Result<(fs::File, u64, u64), RandomAccessError> {
+    let filename = self.filename.clone();
+    let read_only = self.read_only;
+    let open_policy = self.open_policy;
+    let lock_mode = self.lock_mode;
+    let lock_timeout = self.lock_timeout;
+    let direct_io = self.direct_io;
+    let preallocate_length = self.preallocate;
+    unblock(move || {
+      if read_only
+        && matches!(
+          open_policy,
+          OpenPolicy::MustBeNew | OpenPolicy::TruncateExisting
+        )
+      {
+        return Err(
+          DiskError::IncompatibleOptions(
+            "read-only storage can't be created or truncated on open",
+          )
+          .into(),
+        );
+      }
+      if read_only && preallocate_length > 0 {
+        return Err(
+          DiskError::IncompatibleOptions(
+            "read-only storage can't be preallocated",
+          )
+          .into(),
+        );
+      }
+
+      let mut options = OpenOptions::new();
+      options.read(true);
+      if !read_only {
+        options.write(true);
+        match open_policy {
+          OpenPolicy::CreateIfMissing => {
+            options.create(true).truncate(false);
+          }
+          // Truncating is done only after locking, below.
+          OpenPolicy::MustExist | OpenPolicy::TruncateExisting => {}
+          OpenPolicy::MustBeNew => {
+            options.create_new(true);
+          }
+        }
+        if matches!(
+          open_policy,
+          OpenPolicy::CreateIfMissing | OpenPolicy::MustBeNew
+        ) {
+          if let Some(dirname) = filename.parent() {
+            mkdirp::mkdirp(dirname)?;
+          }
+        }
+      }
+      if direct_io {
+        direct::set_open_options(&mut options)?;
+      }
+      let mut file =
+        options.open(&filename).map_err(|err| match err.kind() {
+          std::io::ErrorKind::NotFound => DiskError::NotFound {
+            path: filename.clone(),
+          }
+          .into(),
+          std::io::ErrorKind::AlreadyExists => DiskError::AlreadyExists {
+            path: filename.clone(),
+          }
+          .into(),
+          _ => RandomAccessError::from(err),
+        })?;
+      if direct_io {
+        direct::after_open(&file)?;
+      }
+      lock::lock(&file, &filename, lock_mode, lock_timeout)?;
+      if open_policy == OpenPolicy::TruncateExisting {
+        file.set_len(0)?;
+      }
+      if !read_only {
+        file.sync_all()?;
+        set_sparse(&mut file)?;
+        if preallocate_length > 0 {
+          preallocate(&file, 0, preallocate_length)?;
+        }
+      }
+
+      let (length, block_size) = get_length_and_block_size(&file)?;
+      Ok((file, length, block_size))
     })
+    .await
   }
 }
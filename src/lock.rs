@@ -0,0 +1,165 @@
+use crate::DiskError;
+use random_access_storage::RandomAccessError;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Advisory lock taken on the file when opening, see [crate::Builder::lock].
+///
+/// Uses `flock` on unix and `LockFileEx` on windows. The lock is held until
+/// the storage is closed or dropped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockMode {
+  /// Don't lock the file.
+  #[default]
+  None,
+  /// Shared lock. Any number of shared locks can be held at once, but no
+  /// exclusive ones.
+  Shared,
+  /// Exclusive lock. No other lock can be held at the same time.
+  Exclusive,
+}
+
+/// Lock `file` with `mode`, retrying until `timeout` has passed if it is
+/// locked by someone else.
+pub fn lock(
+  file: &fs::File,
+  path: &Path,
+  mode: LockMode,
+  timeout: Option<Duration>,
+) -> Result<(), RandomAccessError> {
+  let deadline = timeout.map(|timeout| Instant::now() + timeout);
+  let mut backoff = Duration::from_millis(1);
+  loop {
+    let exclusive = match mode {
+      LockMode::None => return Ok(()),
+      LockMode::Shared => false,
+      LockMode::Exclusive => true,
+    };
+    match try_lock(file, exclusive) {
+      Ok(true) => return Ok(()),
+      Err(err) => return Err(err.into()),
+      Ok(false) => {
+        let now = Instant::now();
+        match deadline {
+          Some(deadline) if now < deadline => {
+            std::thread::sleep(backoff.min(deadline - now));
+            backoff = (backoff * 2).min(Duration::from_millis(100));
+          }
+          _ => {
+            return Err(
+              DiskError::Locked {
+                path: path.to_path_buf(),
+              }
+              .into(),
+            )
+          }
+        }
+      }
+    }
+  }
+}
+
+/// Try to lock `file` without waiting. Returns false if someone else holds
+/// a conflicting lock.
+#[cfg(unix)]
+fn try_lock(file: &fs::File, exclusive: bool) -> io::Result<bool> {
+  use std::os::unix::io::AsRawFd;
+
+  let operation = if exclusive {
+    libc::LOCK_EX
+  } else {
+    libc::LOCK_SH
+  };
+  let ret = unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) };
+  if ret == 0 {
+    return Ok(true);
+  }
+  let err = io::Error::last_os_error();
+  if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+    return Ok(false);
+  }
+  Err(err)
+}
+
+/// Release the lock taken with [lock].
+#[cfg(unix)]
+pub fn unlock(file: &fs::File) -> io::Result<()> {
+  use std::os::unix::io::AsRawFd;
+
+  if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) } < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+/// Try to lock `file` without waiting. Returns false if someone else holds
+/// a conflicting lock.
+#[cfg(windows)]
+fn try_lock(file: &fs::File, exclusive: bool) -> io::Result<bool> {
+  use std::os::windows::io::AsRawHandle;
+  use winapi::um::fileapi::LockFileEx;
+  use winapi::um::minwinbase::{
+    LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY, OVERLAPPED,
+  };
+  // Not in winapi without the winerror feature.
+  const ERROR_LOCK_VIOLATION: i32 = 33;
+
+  let mut flags = LOCKFILE_FAIL_IMMEDIATELY;
+  if exclusive {
+    flags |= LOCKFILE_EXCLUSIVE_LOCK;
+  }
+  let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+  let ret = unsafe {
+    LockFileEx(
+      file.as_raw_handle() as _,
+      flags,
+      0,
+      u32::MAX,
+      u32::MAX,
+      &mut overlapped,
+    )
+  };
+  if ret != 0 {
+    return Ok(true);
+  }
+  let err = io::Error::last_os_error();
+  if err.raw_os_error() == Some(ERROR_LOCK_VIOLATION) {
+    return Ok(false);
+  }
+  Err(err)
+}
+
+/// Release the lock taken with [lock].
+#[cfg(windows)]
+pub fn unlock(file: &fs::File) -> io::Result<()> {
+  use std::os::windows::io::AsRawHandle;
+  use winapi::um::fileapi::UnlockFileEx;
+  use winapi::um::minwinbase::OVERLAPPED;
+
+  let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+  let ret = unsafe {
+    UnlockFileEx(
+      file.as_raw_handle() as _,
+      0,
+      u32::MAX,
+      u32::MAX,
+      &mut overlapped,
+    )
+  };
+  if ret == 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn try_lock(_file: &fs::File, _exclusive: bool) -> io::Result<bool> {
+  Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn unlock(_file: &fs::File) -> io::Result<()> {
+  Ok(())
+}
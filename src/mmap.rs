@@ -0,0 +1,414 @@
+use crate::runtime::unblock;
+use crate::sync_policy::{SyncKind, SyncState};
+use crate::trim_mode::TrimState;
+use crate::{
+  positional, trim, Builder, DiskError, DropStrategy, RandomAccessBatch,
+  TrimMode,
+};
+use memmap2::{MmapMut, MmapOptions};
+use random_access_storage::{RandomAccess, RandomAccessError};
+use std::fs;
+use std::io;
+use std::path;
+use std::sync::Arc;
+
+/// Memory-mapped storage, interchangeable with [crate::RandomAccessDisk] and
+/// using the same file format.
+///
+/// Reads and writes copy straight from and to the mapping, without a
+/// syscall or a trip to the blocking thread pool, which pays off when the
+/// same pages are read over and over. Growing, truncating and deleting
+/// change the file and then remap it, so appending in small pieces is
+/// slower than with [crate::RandomAccessDisk].
+///
+/// **NB**: Truncating the file from outside while it's mapped makes reads
+/// past the new end crash the process with `SIGBUS` on unix. Use
+/// [crate::Builder::lock] to keep other processes out.
+///
+/// Create one with [RandomAccessMmap::open] or [crate::Builder::build_mmap].
+#[derive(Debug)]
+pub struct RandomAccessMmap {
+  filename: path::PathBuf,
+  file: Option<Arc<fs::File>>,
+  // The whole file as it was when mapped, or None when the file is empty or
+  // has changed size since.
+  map: Option<MmapMut>,
+  length: u64,
+  block_size: u64,
+  sync: SyncState,
+  read_only: bool,
+  locked: bool,
+  drop_strategy: DropStrategy,
+  trim: Arc<TrimState>,
+}
+
+impl RandomAccessMmap {
+  /// Create a new (auto-sync) instance to storage at `filename`.
+  pub async fn open(
+    filename: impl AsRef<path::Path>,
+  ) -> Result<RandomAccessMmap, RandomAccessError> {
+    Builder::new(filename).build_mmap().await
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  pub(crate) fn new(
+    filename: path::PathBuf,
+    file: fs::File,
+    length: u64,
+    block_size: u64,
+    sync: SyncState,
+    read_only: bool,
+    locked: bool,
+    drop_strategy: DropStrategy,
+    trim_mode: TrimMode,
+  ) -> Self {
+    Self {
+      filename,
+      file: Some(Arc::new(file)),
+      map: None,
+      length,
+      block_size,
+      sync,
+      read_only,
+      locked,
+      drop_strategy,
+      trim: Arc::new(TrimState::new(trim_mode)),
+    }
+  }
+
+  /// Sync all changes to disk, release the lock taken with
+  /// [crate::Builder::lock] and close the file. Same as
+  /// [crate::RandomAccessDisk::close].
+  pub async fn close(&mut self) -> Result<(), RandomAccessError> {
+    let Some(file) = self.file.take() else {
+      return Ok(());
+    };
+    let map = self.map.take();
+    let dirty = self.sync.is_dirty();
+    let mark = self.sync.mark();
+    let locked = self.locked;
+    unblock(move || {
+      if dirty {
+        if let Some(map) = &map {
+          map.flush()?;
+        }
+        file.sync_all()?;
+      }
+      drop(map);
+      if locked {
+        crate::lock::unlock(&file)?;
+      }
+      Ok::<(), RandomAccessError>(())
+    })
+    .await?;
+    self.sync.synced(mark);
+    Ok(())
+  }
+
+  fn file(&self) -> Result<Arc<fs::File>, RandomAccessError> {
+    self.file.clone().ok_or_else(|| DiskError::Closed.into())
+  }
+
+  /// The mapping, mapping the file first if needed.
+  async fn map(&mut self) -> Result<&mut [u8], RandomAccessError> {
+    let file = self.file()?;
+    if self.map.is_none() && self.length > 0 {
+      let read_only = self.read_only;
+      self.map = unblock(move || {
+        // Map what's really there rather than the cached length, so that a
+        // file shrunk by someone else shows up as short reads.
+        let length = file.metadata()?.len();
+        if length == 0 {
+          return Ok::<_, RandomAccessError>(None);
+        }
+        let length = to_usize(length)?;
+        // SAFETY: the file could be changed from outside while mapped, see
+        // the docs on RandomAccessMmap.
+        let map = unsafe {
+          if read_only {
+            // Private, so it works without write access. Never written to.
+            MmapOptions::new().len(length).map_copy(&*file)?
+          } else {
+            MmapOptions::new().len(length).map_mut(&*file)?
+          }
+        };
+        Ok(Some(map))
+      })
+      .await?;
+    }
+    Ok(self.map.as_deref_mut().unwrap_or_default())
+  }
+
+  /// Change the file with `op` on the blocking thread pool, unmapping it
+  /// first since its size may change, then sync if asked to.
+  async fn change<F>(
+    &mut self,
+    op: F,
+    sync: Option<SyncKind>,
+  ) -> Result<(), RandomAccessError>
+  where
+    F: FnOnce(&fs::File) -> Result<(), RandomAccessError> + Send + 'static,
+  {
+    let file = self.file()?;
+    // Some platforms don't allow resizing a file while it's mapped.
+    self.map = None;
+    unblock(move || {
+      op(&file)?;
+      if let Some(kind) = sync {
+        kind.sync(&file)?;
+      }
+      Ok(())
+    })
+    .await
+  }
+
+  /// Sync the mapping and then the file as `kind` says.
+  async fn flush(&mut self, kind: SyncKind) -> Result<(), RandomAccessError> {
+    let file = self.file()?;
+    let map = self.map.take();
+    let (map, result) = unblock(move || {
+      let result = match &map {
+        Some(map) => map.flush(),
+        None => Ok(()),
+      }
+      .and_then(|_| kind.sync(&file));
+      (map, result)
+    })
+    .await;
+    self.map = map;
+    result?;
+    Ok(())
+  }
+
+  /// The mapped `length` bytes at `offset`.
+  async fn slice(
+    &mut self,
+    offset: u64,
+    length: u64,
+  ) -> Result<&[u8], RandomAccessError> {
+    if offset + length > self.length {
+      return Err(RandomAccessError::OutOfBounds {
+        offset,
+        end: Some(offset + length),
+        length: self.length,
+      });
+    }
+
+    let map = self.map().await?;
+    let end = offset + length;
+    let (start, end) = (to_usize(offset)?, to_usize(end)?);
+    if end > map.len() {
+      return Err(
+        DiskError::ShortRead {
+          offset,
+          expected: length,
+          actual: (map.len() as u64).saturating_sub(offset),
+        }
+        .into(),
+      );
+    }
+    Ok(&map[start..end])
+  }
+
+  fn check_writable(&self) -> Result<(), RandomAccessError> {
+    if self.read_only {
+      return Err(DiskError::ReadOnly.into());
+    }
+    Ok(())
+  }
+}
+
+#[async_trait::async_trait]
+impl RandomAccess for RandomAccessMmap {
+  async fn write(
+    &mut self,
+    offset: u64,
+    data: &[u8],
+  ) -> Result<(), RandomAccessError> {
+    self.write_batch(&[(offset, data)]).await
+  }
+
+  async fn read(
+    &mut self,
+    offset: u64,
+    length: u64,
+  ) -> Result<Vec<u8>, RandomAccessError> {
+    Ok(self.slice(offset, length).await?.to_vec())
+  }
+
+  async fn del(
+    &mut self,
+    offset: u64,
+    length: u64,
+  ) -> Result<(), RandomAccessError> {
+    self.check_writable()?;
+    if offset > self.length {
+      return Err(RandomAccessError::OutOfBounds {
+        offset,
+        end: None,
+        length: self.length,
+      });
+    };
+
+    if length == 0 {
+      // No-op
+      return Ok(());
+    }
+
+    // Delete is truncate if up to the current length or more is deleted
+    if offset + length >= self.length {
+      return self.truncate(offset).await;
+    }
+
+    let block_size = self.block_size;
+    let state = self.trim.clone();
+    let sync = self.sync.record();
+    self
+      .change(
+        move |file| {
+          state.trim(
+            || trim(file, offset, length, block_size),
+            || positional::write_zeros(file, offset, length),
+          )
+        },
+        sync.map(|(kind, _)| kind),
+      )
+      .await?;
+    if let Some((_, mark)) = sync {
+      self.sync.synced(mark);
+    }
+    Ok(())
+  }
+
+  async fn truncate(&mut self, length: u64) -> Result<(), RandomAccessError> {
+    self.check_writable()?;
+    let sync = self.sync.record();
+    self
+      .change(
+        move |file| {
+          file.set_len(length)?;
+          Ok(())
+        },
+        sync.map(|(kind, _)| kind),
+      )
+      .await?;
+    self.length = length;
+    if let Some((_, mark)) = sync {
+      self.sync.synced(mark);
+    }
+    Ok(())
+  }
+
+  async fn len(&mut self) -> Result<u64, RandomAccessError> {
+    self.file()?;
+    Ok(self.length)
+  }
+
+  async fn is_empty(&mut self) -> Result<bool, RandomAccessError> {
+    self.file()?;
+    Ok(self.length == 0)
+  }
+
+  async fn sync_all(&mut self) -> Result<(), RandomAccessError> {
+    self.file()?;
+    if self.sync.is_dirty() {
+      let mark = self.sync.mark();
+      self.flush(SyncKind::All).await?;
+      self.sync.synced(mark);
+    }
+    Ok(())
+  }
+}
+
+/// Grows the file and syncs only once per batch, and reads straight from the
+/// mapping.
+#[async_trait::async_trait]
+impl RandomAccessBatch for RandomAccessMmap {
+  async fn write_batch(
+    &mut self,
+    ops: &[(u64, &[u8])],
+  ) -> Result<(), RandomAccessError> {
+    self.check_writable()?;
+    self.file()?;
+    if ops.is_empty() {
+      return Ok(());
+    }
+    let end = ops
+      .iter()
+      .map(|(offset, data)| offset + data.len() as u64)
+      .max()
+      .unwrap_or(0);
+    let sync = self.sync.record();
+    if end > self.length {
+      // Sync after copying the data in below instead.
+      self
+        .change(
+          move |file| {
+            file.set_len(end)?;
+            Ok(())
+          },
+          None,
+        )
+        .await?;
+      self.length = end;
+    }
+    for (offset, data) in ops {
+      if data.is_empty() {
+        continue;
+      }
+      let map = self.map().await?;
+      let end = offset + data.len() as u64;
+      let (start, end) = (to_usize(*offset)?, to_usize(end)?);
+      if end > map.len() {
+        // Only someone else can have made the file shorter than the length
+        // just set.
+        return Err(
+          DiskError::ModifiedExternally {
+            path: self.filename.clone(),
+          }
+          .into(),
+        );
+      }
+      map[start..end].copy_from_slice(data);
+    }
+    if let Some((kind, mark)) = sync {
+      self.flush(kind).await?;
+      self.sync.synced(mark);
+    }
+    Ok(())
+  }
+
+  async fn read_into(
+    &mut self,
+    offset: u64,
+    buffer: &mut [u8],
+  ) -> Result<(), RandomAccessError> {
+    buffer.copy_from_slice(self.slice(offset, buffer.len() as u64).await?);
+    Ok(())
+  }
+}
+
+impl Drop for RandomAccessMmap {
+  fn drop(&mut self) {
+    // Unmapping leaves the changes in the page cache, so syncing the file
+    // afterwards covers them.
+    self.map = None;
+    if let (Some(file), true) = (self.file.take(), self.sync.is_dirty()) {
+      self
+        .drop_strategy
+        .on_unsynced_drop(file, &self.filename, None);
+    }
+  }
+}
+
+/// `value` as an index into a mapping, failing where it doesn't fit in the
+/// address space, as with files over 4GB on 32-bit targets.
+fn to_usize(value: u64) -> Result<usize, RandomAccessError> {
+  usize::try_from(value).map_err(|_| {
+    io::Error::new(
+      io::ErrorKind::InvalidInput,
+      format!("Offset {value} is too large to map on this platform"),
+    )
+    .into()
+  })
+}
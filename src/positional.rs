@@ -0,0 +1,270 @@
+//! Positional (stateless) file I/O: `pread`/`pwrite` on unix and
+//! `seek_read`/`seek_write` on windows. None of these use or mutate a shared
+//! file cursor, so the same [fs::File] can be used from many threads at once.
+
+use std::fs;
+use std::io::{self, IoSlice, IoSliceMut};
+
+/// Read into `buf` from `offset`, returning how many bytes were read.
+#[cfg(unix)]
+pub fn read_at(
+  file: &fs::File,
+  buf: &mut [u8],
+  offset: u64,
+) -> io::Result<usize> {
+  use std::os::unix::fs::FileExt;
+  file.read_at(buf, offset)
+}
+
+/// Read into `buf` from `offset`, returning how many bytes were read.
+#[cfg(windows)]
+pub fn read_at(
+  file: &fs::File,
+  buf: &mut [u8],
+  offset: u64,
+) -> io::Result<usize> {
+  use std::os::windows::fs::FileExt;
+  file.seek_read(buf, offset)
+}
+
+/// Read into `buf` from `offset`, returning how many bytes were read.
+///
+/// Platforms without positional I/O fall back to seeking.
+#[cfg(not(any(unix, windows)))]
+pub fn read_at(
+  mut file: &fs::File,
+  buf: &mut [u8],
+  offset: u64,
+) -> io::Result<usize> {
+  use std::io::{Read, Seek, SeekFrom};
+  file.seek(SeekFrom::Start(offset))?;
+  file.read(buf)
+}
+
+/// Read from `offset` until `buf` is full or the end of the file is
+/// reached, returning how many bytes were read.
+pub fn read_fully_at(
+  file: &fs::File,
+  buf: &mut [u8],
+  offset: u64,
+) -> io::Result<usize> {
+  let mut filled = 0;
+  while filled < buf.len() {
+    match read_at(file, &mut buf[filled..], offset + filled as u64) {
+      Ok(0) => break,
+      Ok(n) => filled += n,
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(filled)
+}
+
+/// Write all of `data` at `offset`.
+#[cfg(unix)]
+pub fn write_all_at(
+  file: &fs::File,
+  data: &[u8],
+  offset: u64,
+) -> io::Result<()> {
+  use std::os::unix::fs::FileExt;
+  file.write_all_at(data, offset)
+}
+
+/// Write all of `data` at `offset`.
+#[cfg(windows)]
+pub fn write_all_at(
+  file: &fs::File,
+  mut data: &[u8],
+  mut offset: u64,
+) -> io::Result<()> {
+  use std::os::windows::fs::FileExt;
+  while !data.is_empty() {
+    match file.seek_write(data, offset) {
+      Ok(0) => {
+        return Err(io::Error::new(
+          io::ErrorKind::WriteZero,
+          "failed to write whole buffer",
+        ))
+      }
+      Ok(n) => {
+        data = &data[n..];
+        offset += n as u64;
+      }
+      Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(())
+}
+
+/// Write all of `data` at `offset`.
+///
+/// Platforms without positional I/O fall back to seeking.
+#[cfg(not(any(unix, windows)))]
+pub fn write_all_at(
+  mut file: &fs::File,
+  data: &[u8],
+  offset: u64,
+) -> io::Result<()> {
+  use std::io::{Seek, SeekFrom, Write};
+  file.seek(SeekFrom::Start(offset))?;
+  file.write_all(data)
+}
+
+/// Write `length` zeros at `offset`, at most 1MiB at a time.
+pub fn write_zeros(
+  file: &fs::File,
+  offset: u64,
+  length: u64,
+) -> io::Result<()> {
+  let zeros = vec![0; length.min(1 << 20) as usize];
+  let mut written = 0;
+  while written < length {
+    let len = (length - written).min(zeros.len() as u64) as usize;
+    write_all_at(file, &zeros[..len], offset + written)?;
+    written += len as u64;
+  }
+  Ok(())
+}
+
+/// Write all of `bufs`, one after the other, at `offset`.
+#[cfg(any(
+  target_os = "linux",
+  target_os = "android",
+  target_os = "freebsd",
+  target_os = "macos",
+))]
+pub fn write_all_vectored_at(
+  file: &fs::File,
+  mut bufs: &mut [IoSlice<'_>],
+  mut offset: u64,
+) -> io::Result<()> {
+  use std::os::unix::io::AsRawFd;
+  // The smallest limit on the number of buffers of the platforms above.
+  const IOV_MAX: usize = 1024;
+
+  IoSlice::advance_slices(&mut bufs, 0);
+  while !bufs.is_empty() {
+    let count = bufs.len().min(IOV_MAX);
+    // SAFETY: IoSlice is guaranteed to be ABI compatible with iovec.
+    let ret = unsafe {
+      libc::pwritev(
+        file.as_raw_fd(),
+        bufs.as_ptr() as *const libc::iovec,
+        count as libc::c_int,
+        offset as libc::off_t,
+      )
+    };
+    if ret < 0 {
+      let err = io::Error::last_os_error();
+      if err.kind() == io::ErrorKind::Interrupted {
+        continue;
+      }
+      return Err(err);
+    }
+    if ret == 0 {
+      return Err(io::Error::new(
+        io::ErrorKind::WriteZero,
+        "failed to write whole buffer",
+      ));
+    }
+    offset += ret as u64;
+    IoSlice::advance_slices(&mut bufs, ret as usize);
+  }
+  Ok(())
+}
+
+/// Write all of `bufs`, one after the other, at `offset`.
+///
+/// Platforms without `pwritev` write them one by one.
+#[cfg(not(any(
+  target_os = "linux",
+  target_os = "android",
+  target_os = "freebsd",
+  target_os = "macos",
+)))]
+pub fn write_all_vectored_at(
+  file: &fs::File,
+  bufs: &mut [IoSlice<'_>],
+  mut offset: u64,
+) -> io::Result<()> {
+  for buf in bufs.iter() {
+    write_all_at(file, buf, offset)?;
+    offset += buf.len() as u64;
+  }
+  Ok(())
+}
+
+/// Read into `bufs`, one after the other, from `offset` until they're full
+/// or the end of the file is reached, returning how many bytes were read.
+#[cfg(any(
+  target_os = "linux",
+  target_os = "android",
+  target_os = "freebsd",
+  target_os = "macos",
+))]
+pub fn read_fully_vectored_at(
+  file: &fs::File,
+  mut bufs: &mut [IoSliceMut<'_>],
+  offset: u64,
+) -> io::Result<usize> {
+  use std::os::unix::io::AsRawFd;
+  // The smallest limit on the number of buffers of the platforms above.
+  const IOV_MAX: usize = 1024;
+
+  let mut filled = 0;
+  IoSliceMut::advance_slices(&mut bufs, 0);
+  while !bufs.is_empty() {
+    let count = bufs.len().min(IOV_MAX);
+    // SAFETY: IoSliceMut is guaranteed to be ABI compatible with iovec.
+    let ret = unsafe {
+      libc::preadv(
+        file.as_raw_fd(),
+        bufs.as_mut_ptr() as *mut libc::iovec as *const libc::iovec,
+        count as libc::c_int,
+        (offset + filled as u64) as libc::off_t,
+      )
+    };
+    if ret < 0 {
+      let err = io::Error::last_os_error();
+      if err.kind() == io::ErrorKind::Interrupted {
+        continue;
+      }
+      return Err(err);
+    }
+    if ret == 0 {
+      break;
+    }
+    filled += ret as usize;
+    IoSliceMut::advance_slices(&mut bufs, ret as usize);
+  }
+  Ok(filled)
+}
+
+/// Read into `bufs`, one after the other, from `offset` until they're full
+/// or the end of the file is reached, returning how many bytes were read.
+///
+/// Platforms without `preadv` read them one by one.
+#[cfg(not(any(
+  target_os = "linux",
+  target_os = "android",
+  target_os = "freebsd",
+  target_os = "macos",
+)))]
+pub fn read_fully_vectored_at(
+  file: &fs::File,
+  bufs: &mut [IoSliceMut<'_>],
+  offset: u64,
+) -> io::Result<usize> {
+  let mut filled = 0;
+  for buf in bufs.iter_mut() {
+    let len = buf.len();
+    let n = read_fully_at(file, buf, offset + filled as u64)?;
+    filled += n;
+    if n < len {
+      break;
+    }
+  }
+  Ok(filled)
+}
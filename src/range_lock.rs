@@ -0,0 +1,110 @@
+//! Async reader/writer lock over byte ranges of a file.
+//!
+//! Shared (read) acquisitions only conflict with exclusive (write)
+//! acquisitions over overlapping ranges. Conflicting acquisitions are granted
+//! in the order they were requested, so a write can't be starved by a steady
+//! stream of reads and overlapping writes land in the order they were issued.
+
+use event_listener::Event;
+use std::ops::Range;
+use std::sync::Mutex;
+
+#[derive(Debug, Default)]
+pub struct RangeLock {
+  state: Mutex<State>,
+  released: Event,
+}
+
+#[derive(Debug, Default)]
+struct State {
+  next_ticket: u64,
+  // Both waiting and granted acquisitions, in ticket order.
+  entries: Vec<Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+  ticket: u64,
+  range: Range<u64>,
+  exclusive: bool,
+}
+
+impl Entry {
+  fn conflicts_with(&self, other: &Entry) -> bool {
+    (self.exclusive || other.exclusive)
+      && self.range.start < other.range.end
+      && other.range.start < self.range.end
+  }
+}
+
+impl RangeLock {
+  /// Lock `range` for reading.
+  pub async fn read(&self, range: Range<u64>) -> RangeGuard<'_> {
+    self.acquire(range, false).await
+  }
+
+  /// Lock `range` for writing.
+  pub async fn write(&self, range: Range<u64>) -> RangeGuard<'_> {
+    self.acquire(range, true).await
+  }
+
+  async fn acquire(
+    &self,
+    range: Range<u64>,
+    exclusive: bool,
+  ) -> RangeGuard<'_> {
+    let ticket = {
+      let mut state = self.state.lock().unwrap();
+      let ticket = state.next_ticket;
+      state.next_ticket += 1;
+      state.entries.push(Entry {
+        ticket,
+        range,
+        exclusive,
+      });
+      ticket
+    };
+    // Created before waiting so that a cancelled acquisition still removes
+    // its entry.
+    let guard = RangeGuard { lock: self, ticket };
+    loop {
+      if self.is_free(ticket) {
+        return guard;
+      }
+      let listener = self.released.listen();
+      if self.is_free(ticket) {
+        return guard;
+      }
+      listener.await;
+    }
+  }
+
+  fn is_free(&self, ticket: u64) -> bool {
+    let state = self.state.lock().unwrap();
+    let position = state
+      .entries
+      .iter()
+      .position(|entry| entry.ticket == ticket)
+      .expect("range lock entry missing");
+    let entry = &state.entries[position];
+    !state.entries[..position]
+      .iter()
+      .any(|earlier| earlier.conflicts_with(entry))
+  }
+}
+
+/// Held range, released on drop.
+#[derive(Debug)]
+pub struct RangeGuard<'a> {
+  lock: &'a RangeLock,
+  ticket: u64,
+}
+
+impl Drop for RangeGuard<'_> {
+  fn drop(&mut self) {
+    let mut state = self.lock.state.lock().unwrap();
+    state.entries.retain(|entry| entry.ticket != self.ticket);
+    drop(state);
+    self.lock.released.notify(usize::MAX);
+  }
+}
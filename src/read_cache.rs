@@ -0,0 +1,225 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Which block [crate::Builder::read_cache] drops to make room for another.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+  /// The least recently used one.
+  #[default]
+  Lru,
+  /// The next one not used since the clock hand last passed it. Cheaper to
+  /// keep track of than [EvictionPolicy::Lru], and close to it in practice.
+  Clock,
+}
+
+/// Hit and miss counters of [crate::Builder::read_cache], counted in blocks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadCacheStats {
+  /// Blocks read from the cache.
+  pub hits: u64,
+  /// Blocks that had to be read from disk.
+  pub misses: u64,
+}
+
+/// Cache of blocks of a file, as reads would return them.
+#[derive(Debug)]
+pub struct ReadCache {
+  block_size: u64,
+  capacity: usize,
+  policy: EvictionPolicy,
+  slots: Vec<Slot>,
+  // Block number to its slot.
+  index: HashMap<u64, usize>,
+  // Last use to slot, for EvictionPolicy::Lru.
+  lru: BTreeMap<u64, usize>,
+  tick: u64,
+  // Next slot to look at, for EvictionPolicy::Clock.
+  hand: usize,
+  stats: ReadCacheStats,
+}
+
+#[derive(Debug)]
+struct Slot {
+  block: u64,
+  // Shorter than a block for the last block of the file.
+  data: Vec<u8>,
+  last_used: u64,
+  referenced: bool,
+}
+
+impl ReadCache {
+  /// Cache holding up to `capacity` bytes of blocks of `block_size`.
+  pub fn new(capacity: usize, block_size: u64, policy: EvictionPolicy) -> Self {
+    Self {
+      block_size,
+      capacity: (capacity / block_size as usize).max(1),
+      policy,
+      slots: vec![],
+      index: HashMap::new(),
+      lru: BTreeMap::new(),
+      tick: 0,
+      hand: 0,
+      stats: ReadCacheStats::default(),
+    }
+  }
+
+  pub fn block_size(&self) -> u64 {
+    self.block_size
+  }
+
+  pub fn stats(&self) -> ReadCacheStats {
+    self.stats
+  }
+
+  /// The first `length` bytes of `block`, if cached. Counts a hit or a miss.
+  pub fn get(&mut self, block: u64, length: usize) -> Option<&[u8]> {
+    match self.index.get(&block) {
+      Some(&slot) if self.slots[slot].data.len() >= length => {
+        self.stats.hits += 1;
+        self.touch(slot);
+        Some(&self.slots[slot].data[..length])
+      }
+      _ => {
+        self.stats.misses += 1;
+        None
+      }
+    }
+  }
+
+  /// Cache `data` as the contents of `block`.
+  pub fn insert(&mut self, block: u64, data: Vec<u8>) {
+    if let Some(&slot) = self.index.get(&block) {
+      self.slots[slot].data = data;
+      self.touch(slot);
+      return;
+    }
+    let slot = if self.slots.len() < self.capacity {
+      self.slots.push(Slot {
+        block,
+        data,
+        last_used: 0,
+        referenced: false,
+      });
+      self.slots.len() - 1
+    } else {
+      let slot = self.evict();
+      self.index.remove(&self.slots[slot].block);
+      self.slots[slot].block = block;
+      self.slots[slot].data = data;
+      slot
+    };
+    self.index.insert(block, slot);
+    self.touch(slot);
+  }
+
+  /// Update cached blocks with `data` written at `offset`.
+  pub fn write(&mut self, offset: u64, data: &[u8]) {
+    self.update(offset, data.len() as u64, |cached, from| {
+      cached.copy_from_slice(&data[from..from + cached.len()]);
+    });
+  }
+
+  /// Update cached blocks with `length` bytes at `offset` being deleted.
+  pub fn zero(&mut self, offset: u64, length: u64) {
+    self.update(offset, length, |cached, _| cached.fill(0));
+  }
+
+  /// Update cached blocks with the file being truncated to `length`.
+  pub fn truncate(&mut self, length: u64) {
+    let first = length / self.block_size;
+    let beyond: Vec<u64> = self
+      .index
+      .keys()
+      .copied()
+      .filter(|&block| block > first)
+      .collect();
+    for block in beyond {
+      self.remove(block);
+    }
+    if let Some(&slot) = self.index.get(&first) {
+      let keep = (length - first * self.block_size) as usize;
+      self.slots[slot].data.truncate(keep);
+    }
+  }
+
+  /// Drop all cached blocks, keeping the counters.
+  pub fn clear(&mut self) {
+    self.slots.clear();
+    self.index.clear();
+    self.lru.clear();
+    self.hand = 0;
+  }
+
+  /// Run `update` on the cached bytes within `length` bytes at `offset`,
+  /// along with where they start in that range.
+  fn update(
+    &mut self,
+    offset: u64,
+    length: u64,
+    mut update: impl FnMut(&mut [u8], usize),
+  ) {
+    if length == 0 {
+      return;
+    }
+    let end = offset + length;
+    for block in offset / self.block_size..=(end - 1) / self.block_size {
+      let Some(&slot) = self.index.get(&block) else {
+        continue;
+      };
+      let block_start = block * self.block_size;
+      let data = &mut self.slots[slot].data;
+      let from = offset.max(block_start);
+      let to = end.min(block_start + data.len() as u64);
+      if from < to {
+        update(
+          &mut data[(from - block_start) as usize..(to - block_start) as usize],
+          (from - offset) as usize,
+        );
+      }
+    }
+  }
+
+  fn touch(&mut self, slot: usize) {
+    match self.policy {
+      EvictionPolicy::Lru => {
+        self.lru.remove(&self.slots[slot].last_used);
+        self.tick += 1;
+        self.slots[slot].last_used = self.tick;
+        self.lru.insert(self.tick, slot);
+      }
+      EvictionPolicy::Clock => self.slots[slot].referenced = true,
+    }
+  }
+
+  /// Pick a slot to reuse.
+  fn evict(&mut self) -> usize {
+    match self.policy {
+      EvictionPolicy::Lru => self.lru.pop_first().unwrap().1,
+      EvictionPolicy::Clock => loop {
+        let slot = self.hand;
+        self.hand = (self.hand + 1) % self.slots.len();
+        if !std::mem::take(&mut self.slots[slot].referenced) {
+          break slot;
+        }
+      },
+    }
+  }
+
+  fn remove(&mut self, block: u64) {
+    let Some(slot) = self.index.remove(&block) else {
+      return;
+    };
+    let removed = self.slots.swap_remove(slot);
+    if self.policy == EvictionPolicy::Lru {
+      self.lru.remove(&removed.last_used);
+    }
+    if let Some(moved) = self.slots.get(slot) {
+      self.index.insert(moved.block, slot);
+      if self.policy == EvictionPolicy::Lru {
+        self.lru.insert(moved.last_used, slot);
+      }
+    }
+    if self.hand >= self.slots.len() {
+      self.hand = 0;
+    }
+  }
+}
@@ -0,0 +1,141 @@
+//! Glue to the async runtimes enabled with cargo features, any number of
+//! which can be enabled at once.
+//!
+//! Blocking work goes to the blocking thread pool of tokio when running
+//! within it, and otherwise to that of async-std or smol, in that order.
+//! With none of them enabled, or with only tokio outside of it, the work is
+//! done in place, blocking the task. So is all work under [block_on].
+
+use std::cell::Cell;
+use std::future::Future;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+thread_local! {
+  // Whether blocking work is done in place on this thread.
+  static INLINE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `future` to completion on the current thread, doing blocking work in
+/// place rather than on a thread pool.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+  let _restore = Restore(INLINE.with(|inline| inline.replace(true)));
+  let mut future = std::pin::pin!(future);
+  let waker = Waker::from(Arc::new(Unparker(thread::current())));
+  let mut cx = Context::from_waker(&waker);
+  loop {
+    match future.as_mut().poll(&mut cx) {
+      Poll::Ready(output) => return output,
+      Poll::Pending => thread::park(),
+    }
+  }
+}
+
+/// Restores whether blocking work is done in place when dropped, even when
+/// unwinding.
+struct Restore(bool);
+
+impl Drop for Restore {
+  fn drop(&mut self) {
+    INLINE.with(|inline| inline.set(self.0));
+  }
+}
+
+struct Unparker(Thread);
+
+impl Wake for Unparker {
+  fn wake(self: Arc<Self>) {
+    self.0.unpark();
+  }
+
+  fn wake_by_ref(self: &Arc<Self>) {
+    self.0.unpark();
+  }
+}
+
+/// Run the blocking closure `f` on a blocking thread pool and wait for its
+/// result.
+pub async fn unblock<F, T>(f: F) -> T
+where
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
+{
+  if INLINE.with(Cell::get) {
+    return f();
+  }
+  #[cfg(feature = "tokio")]
+  if let Ok(handle) = tokio::runtime::Handle::try_current() {
+    return handle
+      .spawn_blocking(f)
+      .await
+      .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()));
+  }
+  unblock_elsewhere(f).await
+}
+
+/// Run the blocking closure `f` on a blocking thread pool without waiting
+/// for it, or right away where there is none.
+pub fn spawn_blocking_detached<F>(f: F)
+where
+  F: FnOnce() + Send + 'static,
+{
+  #[cfg(feature = "tokio")]
+  if let Ok(handle) = tokio::runtime::Handle::try_current() {
+    drop(handle.spawn_blocking(f));
+    return;
+  }
+  spawn_detached_elsewhere(f)
+}
+
+#[cfg(feature = "async-std")]
+async fn unblock_elsewhere<F, T>(f: F) -> T
+where
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
+{
+  async_std::task::spawn_blocking(f).await
+}
+
+#[cfg(all(feature = "smol", not(feature = "async-std")))]
+async fn unblock_elsewhere<F, T>(f: F) -> T
+where
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
+{
+  ::blocking::unblock(f).await
+}
+
+#[cfg(not(any(feature = "async-std", feature = "smol")))]
+async fn unblock_elsewhere<F, T>(f: F) -> T
+where
+  F: FnOnce() -> T + Send + 'static,
+  T: Send + 'static,
+{
+  f()
+}
+
+#[cfg(feature = "async-std")]
+fn spawn_detached_elsewhere<F>(f: F)
+where
+  F: FnOnce() + Send + 'static,
+{
+  // Dropping the handle detaches the task.
+  drop(async_std::task::spawn_blocking(f));
+}
+
+#[cfg(all(feature = "smol", not(feature = "async-std")))]
+fn spawn_detached_elsewhere<F>(f: F)
+where
+  F: FnOnce() + Send + 'static,
+{
+  ::blocking::unblock(f).detach();
+}
+
+#[cfg(not(any(feature = "async-std", feature = "smol")))]
+fn spawn_detached_elsewhere<F>(f: F)
+where
+  F: FnOnce() + Send + 'static,
+{
+  f()
+}
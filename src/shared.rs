@@ -0,0 +1,332 @@
+use crate::range_lock::RangeLock;
+use crate::runtime::unblock;
+use crate::sync_policy::SyncState;
+use crate::trim_mode::TrimState;
+use crate::{
+  positional, trim, DiskError, DropStrategy, RandomAccessBatch, SyncPolicy,
+  TrimMode,
+};
+use random_access_storage::{RandomAccess, RandomAccessError};
+use std::fs;
+use std::path;
+use std::sync::{Arc, Mutex};
+
+/// Cloneable handle to a file on disk that can be used from many tasks at
+/// once.
+///
+/// All clones share the same file. Reads run in parallel with each other,
+/// while writes, deletes and truncates wait for operations on overlapping
+/// ranges issued before them, and hold back overlapping operations issued
+/// after them. Create one with [crate::Builder::build_shared].
+#[derive(Debug, Clone)]
+pub struct SharedRandomAccessDisk {
+  inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+  filename: path::PathBuf,
+  file: Arc<fs::File>,
+  length: Mutex<u64>,
+  block_size: u64,
+  sync: Mutex<SyncState>,
+  drop_strategy: DropStrategy,
+  read_only: bool,
+  ranges: RangeLock,
+  trim: Arc<TrimState>,
+}
+
+impl SharedRandomAccessDisk {
+  #[allow(clippy::too_many_arguments)]
+  pub(crate) fn new(
+    filename: path::PathBuf,
+    file: fs::File,
+    length: u64,
+    block_size: u64,
+    sync_policy: SyncPolicy,
+    drop_strategy: DropStrategy,
+    read_only: bool,
+    trim_mode: TrimMode,
+  ) -> Self {
+    Self {
+      inner: Arc::new(Inner {
+        filename,
+        file: Arc::new(file),
+        length: Mutex::new(length),
+        block_size,
+        sync: Mutex::new(SyncState::new(sync_policy)),
+        drop_strategy,
+        read_only,
+        ranges: RangeLock::default(),
+        trim: Arc::new(TrimState::new(trim_mode)),
+      }),
+    }
+  }
+
+  /// Write bytes of `data` at an `offset`.
+  pub async fn write(
+    &self,
+    offset: u64,
+    data: &[u8],
+  ) -> Result<(), RandomAccessError> {
+    self.check_writable()?;
+    let end = offset + (data.len() as u64);
+    if data.is_empty() {
+      // Same as in RandomAccessDisk::write: the file on disk has to grow
+      // along with the length.
+      if end > self.length() {
+        return self.resize(end, true).await;
+      }
+      return Ok(());
+    }
+
+    let _guard = self.inner.ranges.write(offset..end).await;
+    let data = data.to_vec();
+    self
+      .change(move |file| {
+        positional::write_all_at(file, &data, offset)?;
+        Ok(())
+      })
+      .await?;
+
+    let mut length = self.inner.length.lock().unwrap();
+    if end > *length {
+      *length = end;
+    }
+    Ok(())
+  }
+
+  /// Read a sequence of bytes at an `offset`.
+  pub async fn read(
+    &self,
+    offset: u64,
+    length: u64,
+  ) -> Result<Vec<u8>, RandomAccessError> {
+    let end = offset + length;
+    let _guard = self.inner.ranges.read(offset..end).await;
+    let current_length = self.length();
+    if end > current_length {
+      return Err(RandomAccessError::OutOfBounds {
+        offset,
+        end: Some(end),
+        length: current_length,
+      });
+    }
+
+    let file = self.inner.file.clone();
+    unblock(move || {
+      let mut buffer = vec![0; length as usize];
+      let bytes_read = positional::read_fully_at(&file, &mut buffer, offset)?;
+      if bytes_read < buffer.len() {
+        return Err(
+          DiskError::ShortRead {
+            offset,
+            expected: length,
+            actual: bytes_read as u64,
+          }
+          .into(),
+        );
+      }
+      Ok(buffer)
+    })
+    .await
+  }
+
+  /// Delete `length` bytes at `offset`. Same semantics as
+  /// [RandomAccess::del].
+  pub async fn del(
+    &self,
+    offset: u64,
+    length: u64,
+  ) -> Result<(), RandomAccessError> {
+    self.check_writable()?;
+    let current_length = self.length();
+    if offset > current_length {
+      return Err(RandomAccessError::OutOfBounds {
+        offset,
+        end: None,
+        length: current_length,
+      });
+    };
+
+    if length == 0 {
+      // No-op
+      return Ok(());
+    }
+
+    let end = offset + length;
+    let guard = self.inner.ranges.write(offset..end).await;
+    // The length may have changed while waiting for the lock, in which case
+    // deleting might now mean truncating.
+    if end >= self.length() {
+      drop(guard);
+      return self.resize(offset, false).await;
+    }
+
+    let block_size = self.inner.block_size;
+    let state = self.inner.trim.clone();
+    self
+      .change(move |file| {
+        state.trim(
+          || trim(file, offset, length, block_size),
+          || positional::write_zeros(file, offset, length),
+        )
+      })
+      .await
+  }
+
+  /// Resize to `length`.
+  pub async fn truncate(&self, length: u64) -> Result<(), RandomAccessError> {
+    self.check_writable()?;
+    self.resize(length, false).await
+  }
+
+  /// Current length.
+  pub async fn len(&self) -> Result<u64, RandomAccessError> {
+    Ok(self.length())
+  }
+
+  /// Whether the length is zero.
+  pub async fn is_empty(&self) -> Result<bool, RandomAccessError> {
+    Ok(self.length() == 0)
+  }
+
+  /// Flush all changes to disk.
+  pub async fn sync_all(&self) -> Result<(), RandomAccessError> {
+    let mark = {
+      let sync = self.inner.sync.lock().unwrap();
+      if !sync.is_dirty() {
+        return Ok(());
+      }
+      sync.mark()
+    };
+    let file = self.inner.file.clone();
+    unblock(move || file.sync_all()).await?;
+    self.inner.sync.lock().unwrap().synced(mark);
+    Ok(())
+  }
+
+  fn check_writable(&self) -> Result<(), RandomAccessError> {
+    if self.inner.read_only {
+      return Err(DiskError::ReadOnly.into());
+    }
+    Ok(())
+  }
+
+  /// Make a change to the file with `op` on the blocking thread pool,
+  /// followed by whatever syncing the [SyncPolicy] asks for.
+  async fn change<F>(&self, op: F) -> Result<(), RandomAccessError>
+  where
+    F: FnOnce(&fs::File) -> Result<(), RandomAccessError> + Send + 'static,
+  {
+    self.inner.sync.lock().unwrap().begin();
+    let inner = self.inner.clone();
+    unblock(move || {
+      let result = op(&inner.file);
+      // Decided only once the change is done, so that the mark covers
+      // exactly the changes that are done before syncing. Failed changes
+      // count as done too, as they may have changed part of the file, and a
+      // sync could otherwise never catch up with them.
+      let sync = inner.sync.lock().unwrap().complete();
+      result?;
+      if let Some((kind, mark)) = sync {
+        kind.sync(&inner.file)?;
+        inner.sync.lock().unwrap().synced(mark);
+      }
+      Ok(())
+    })
+    .await
+  }
+
+  fn length(&self) -> u64 {
+    *self.inner.length.lock().unwrap()
+  }
+
+  /// Set the length to `new_length`, or only grow to it if `grow_only`.
+  async fn resize(
+    &self,
+    new_length: u64,
+    grow_only: bool,
+  ) -> Result<(), RandomAccessError> {
+    loop {
+      // Everything from the smaller of the old and new lengths on is
+      // affected. If the length shrank below that while waiting for the
+      // lock, the locked range isn't enough any more and we go again.
+      let start = new_length.min(self.length());
+      let _guard = self.inner.ranges.write(start..u64::MAX).await;
+      let current_length = self.length();
+      if new_length.min(current_length) < start {
+        continue;
+      }
+      if grow_only && current_length >= new_length {
+        return Ok(());
+      }
+
+      self
+        .change(move |file| {
+          file.set_len(new_length)?;
+          Ok(())
+        })
+        .await?;
+      *self.inner.length.lock().unwrap() = new_length;
+      return Ok(());
+    }
+  }
+}
+
+#[async_trait::async_trait]
+impl RandomAccess for SharedRandomAccessDisk {
+  async fn write(
+    &mut self,
+    offset: u64,
+    data: &[u8],
+  ) -> Result<(), RandomAccessError> {
+    SharedRandomAccessDisk::write(self, offset, data).await
+  }
+
+  async fn read(
+    &mut self,
+    offset: u64,
+    length: u64,
+  ) -> Result<Vec<u8>, RandomAccessError> {
+    SharedRandomAccessDisk::read(self, offset, length).await
+  }
+
+  async fn del(
+    &mut self,
+    offset: u64,
+    length: u64,
+  ) -> Result<(), RandomAccessError> {
+    SharedRandomAccessDisk::del(self, offset, length).await
+  }
+
+  async fn truncate(&mut self, length: u64) -> Result<(), RandomAccessError> {
+    SharedRandomAccessDisk::truncate(self, length).await
+  }
+
+  async fn len(&mut self) -> Result<u64, RandomAccessError> {
+    SharedRandomAccessDisk::len(self).await
+  }
+
+  async fn is_empty(&mut self) -> Result<bool, RandomAccessError> {
+    SharedRandomAccessDisk::is_empty(self).await
+  }
+
+  async fn sync_all(&mut self) -> Result<(), RandomAccessError> {
+    SharedRandomAccessDisk::sync_all(self).await
+  }
+}
+
+impl RandomAccessBatch for SharedRandomAccessDisk {}
+
+impl Drop for Inner {
+  fn drop(&mut self) {
+    if self.sync.get_mut().unwrap().is_dirty() {
+      self.drop_strategy.on_unsynced_drop(
+        self.file.clone(),
+        &self.filename,
+        None,
+      );
+    }
+  }
+}
@@ -0,0 +1,123 @@
+use std::fs;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// When changes are synced to disk, see [crate::Builder::sync_policy].
+///
+/// Whatever the policy, [random_access_storage::RandomAccess::sync_all]
+/// syncs everything that isn't synced yet, and does nothing if everything
+/// is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+  /// Sync data and metadata (`fsync`) after every change.
+  #[default]
+  Always,
+  /// Sync only data (`fdatasync`) after every change. Metadata that isn't
+  /// needed to read the data back, like modification times, may be lost.
+  DataOnly,
+  /// Sync once this many changes haven't been synced.
+  EveryN(u64),
+  /// Sync on the first change made at least this long after the last sync.
+  /// There's no background timer, so changes made before a quiet period stay
+  /// unsynced until the next change or an explicit sync.
+  EveryDuration(Duration),
+  /// Never sync on its own.
+  Manual,
+}
+
+/// How to sync a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncKind {
+  /// `fsync`
+  All,
+  /// `fdatasync`
+  Data,
+}
+
+impl SyncKind {
+  pub fn sync(self, file: &fs::File) -> io::Result<()> {
+    match self {
+      Self::All => file.sync_all(),
+      Self::Data => file.sync_data(),
+    }
+  }
+}
+
+/// Keeps track of unsynced changes and decides when a [SyncPolicy] wants
+/// them synced.
+///
+/// Changes are counted rather than flagged so that a sync only clears the
+/// changes that were done when it started, even if other changes run
+/// concurrently with it.
+#[derive(Debug)]
+pub struct SyncState {
+  policy: SyncPolicy,
+  started: u64,
+  completed: u64,
+  synced: u64,
+  last_sync: Instant,
+}
+
+impl SyncState {
+  pub fn new(policy: SyncPolicy) -> Self {
+    Self {
+      policy,
+      started: 0,
+      completed: 0,
+      synced: 0,
+      last_sync: Instant::now(),
+    }
+  }
+
+  /// Record that a change is about to be made.
+  pub fn begin(&mut self) {
+    self.started += 1;
+  }
+
+  /// Record that a change started with [SyncState::begin] is done. Returns
+  /// how the policy wants it synced right away, if at all, along with the
+  /// mark to pass to [SyncState::synced] once that's done.
+  pub fn complete(&mut self) -> Option<(SyncKind, u64)> {
+    self.completed += 1;
+    let kind = match self.policy {
+      SyncPolicy::Always => Some(SyncKind::All),
+      SyncPolicy::DataOnly => Some(SyncKind::Data),
+      SyncPolicy::EveryN(n) => {
+        (self.completed - self.synced >= n).then_some(SyncKind::All)
+      }
+      SyncPolicy::EveryDuration(duration) => {
+        (self.last_sync.elapsed() >= duration).then_some(SyncKind::All)
+      }
+      SyncPolicy::Manual => None,
+    };
+    kind.map(|kind| (kind, self.mark()))
+  }
+
+  /// [SyncState::begin] and [SyncState::complete] in one, for when changes
+  /// can't run concurrently and the decision can be made upfront.
+  pub fn record(&mut self) -> Option<(SyncKind, u64)> {
+    self.begin();
+    self.complete()
+  }
+
+  /// Mark to pass to [SyncState::synced] for a sync starting now.
+  pub fn mark(&self) -> u64 {
+    self.completed
+  }
+
+  /// Record that a sync that started at `mark` is done.
+  pub fn synced(&mut self, mark: u64) {
+    self.synced = self.synced.max(mark);
+    self.last_sync = Instant::now();
+  }
+
+  /// Number of changes started so far.
+  pub fn changes(&self) -> u64 {
+    self.started
+  }
+
+  /// Whether there are changes that haven't been synced yet.
+  pub fn is_dirty(&self) -> bool {
+    self.started > self.synced
+  }
+}
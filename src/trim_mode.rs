@@ -0,0 +1,155 @@
+use random_access_storage::RandomAccessError;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// What [random_access_storage::RandomAccess::del] does when the file
+/// system can't punch holes, see [crate::Builder::trim_mode].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TrimMode {
+  /// Fail with the error from the file system.
+  Strict,
+  /// Write zeros instead, and keep doing so without trying to punch holes
+  /// again.
+  #[default]
+  BestEffort,
+}
+
+/// Whether to try punching holes, remembering when the file system can't.
+#[derive(Debug)]
+pub struct TrimState {
+  mode: TrimMode,
+  punch_holes: AtomicBool,
+}
+
+impl TrimState {
+  pub fn new(mode: TrimMode) -> Self {
+    Self {
+      mode,
+      punch_holes: AtomicBool::new(true),
+    }
+  }
+
+  /// Whether punching holes is worth trying.
+  pub fn punch_holes(&self) -> bool {
+    self.punch_holes.load(Ordering::Relaxed)
+  }
+
+  /// Handle `err` from punching a hole. Returns it unless the file system
+  /// doesn't support hole punching and zeros may be written instead, in
+  /// which case that's remembered.
+  pub fn fallback(
+    &self,
+    err: RandomAccessError,
+  ) -> Result<(), RandomAccessError> {
+    let unsupported = match &err {
+      RandomAccessError::IO { source, .. } => is_unsupported(source),
+      _ => false,
+    };
+    if self.mode == TrimMode::Strict || !unsupported {
+      return Err(err);
+    }
+    self.punch_holes.store(false, Ordering::Relaxed);
+    Ok(())
+  }
+
+  /// Trim with `punch` if worth trying, and with `zero` if not or if that
+  /// turns out to be unsupported.
+  pub fn trim(
+    &self,
+    punch: impl FnOnce() -> Result<(), RandomAccessError>,
+    zero: impl FnOnce() -> io::Result<()>,
+  ) -> Result<(), RandomAccessError> {
+    if self.punch_holes() {
+      match punch() {
+        Ok(()) => return Ok(()),
+        Err(err) => self.fallback(err)?,
+      }
+    }
+    zero()?;
+    Ok(())
+  }
+}
+
+/// Whether `source` says the file system doesn't support what was asked of
+/// it, like punching holes or reserving space.
+pub fn is_unsupported(source: &io::Error) -> bool {
+  if source.kind() == io::ErrorKind::Unsupported {
+    return true;
+  }
+  #[cfg(unix)]
+  let codes = [libc::EOPNOTSUPP, libc::ENOTSUP, libc::ENOSYS];
+  // ERROR_INVALID_FUNCTION, from file systems without FSCTL_SET_ZERO_DATA.
+  #[cfg(windows)]
+  let codes = [1];
+  #[cfg(not(any(unix, windows)))]
+  let codes: [i32; 0] = [];
+  source
+    .raw_os_error()
+    .is_some_and(|code| codes.contains(&code))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::cell::Cell;
+
+  fn unsupported() -> RandomAccessError {
+    #[cfg(unix)]
+    let err = io::Error::from_raw_os_error(libc::EOPNOTSUPP);
+    #[cfg(not(unix))]
+    let err = io::Error::from(io::ErrorKind::Unsupported);
+    err.into()
+  }
+
+  /// Trim once with a punch that fails with `err`, returning the result and
+  /// how often punching and zeroing were tried.
+  fn trim(
+    state: &TrimState,
+    err: impl Fn() -> RandomAccessError,
+  ) -> (Result<(), RandomAccessError>, usize, usize) {
+    let (punched, zeroed) = (Cell::new(0), Cell::new(0));
+    let result = state.trim(
+      || {
+        punched.set(punched.get() + 1);
+        Err(err())
+      },
+      || {
+        zeroed.set(zeroed.get() + 1);
+        Ok(())
+      },
+    );
+    (result, punched.get(), zeroed.get())
+  }
+
+  #[test]
+  fn best_effort_zeros_and_stops_punching_when_unsupported() {
+    let state = TrimState::new(TrimMode::BestEffort);
+    let (result, punched, zeroed) = trim(&state, unsupported);
+    assert!(result.is_ok());
+    assert_eq!((punched, zeroed), (1, 1));
+    assert!(!state.punch_holes());
+
+    let (result, punched, zeroed) = trim(&state, unsupported);
+    assert!(result.is_ok());
+    assert_eq!((punched, zeroed), (0, 1));
+  }
+
+  #[test]
+  fn strict_returns_unsupported_error() {
+    let state = TrimState::new(TrimMode::Strict);
+    let (result, punched, zeroed) = trim(&state, unsupported);
+    assert!(result.is_err());
+    assert_eq!((punched, zeroed), (1, 0));
+    assert!(state.punch_holes());
+  }
+
+  #[test]
+  fn best_effort_returns_other_errors() {
+    let state = TrimState::new(TrimMode::BestEffort);
+    let (result, punched, zeroed) =
+      trim(&state, || io::Error::from(io::ErrorKind::Other).into());
+    assert!(result.is_err());
+    assert_eq!((punched, zeroed), (1, 0));
+    assert!(state.punch_holes());
+  }
+}
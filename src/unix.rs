@@ -1,28 +1,89 @@
-#[cfg(feature = "async-std")]
-use async_std::fs;
 use random_access_storage::RandomAccessError;
-#[cfg(feature = "tokio")]
-use tokio::fs;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
 
 /// Get unix file length and file system block size
-pub async fn get_length_and_block_size(
+pub fn get_length_and_block_size(
   file: &fs::File,
 ) -> Result<(u64, u64), RandomAccessError> {
   use std::os::unix::fs::MetadataExt;
-  let meta = file.metadata().await?;
+  let meta = file.metadata()?;
   let block_size = meta.blksize();
   Ok((meta.len(), block_size))
 }
 
+/// Get the space the file takes on disk, which is less than its length
+/// where it has holes
+pub fn allocated_size(
+  file: &fs::File,
+  _path: &Path,
+) -> Result<u64, RandomAccessError> {
+  use std::os::unix::fs::MetadataExt;
+  // Always in 512 byte units, whatever the block size.
+  Ok(file.metadata()?.blocks() * 512)
+}
+
 /// Set file to sparse, not applicable in unix
-pub async fn set_sparse(_file: &mut fs::File) -> Result<(), RandomAccessError> {
+pub fn set_sparse(_file: &mut fs::File) -> Result<(), RandomAccessError> {
   Ok(())
 }
 
+/// Ranges of `length` bytes at `offset` that hold data rather than holes,
+/// found with `SEEK_DATA` and `SEEK_HOLE`. File systems that don't support
+/// them report the whole range as data.
+#[allow(clippy::single_range_in_vec_init)]
+pub fn data_ranges(
+  file: &fs::File,
+  offset: u64,
+  length: u64,
+) -> Result<Vec<Range<u64>>, RandomAccessError> {
+  let end = offset + length;
+  let mut ranges = vec![];
+  let mut position = offset;
+  while position < end {
+    let data = match seek(file, position, libc::SEEK_DATA) {
+      Ok(data) => data,
+      // No data from here on.
+      Err(err) if err.raw_os_error() == Some(libc::ENXIO) => break,
+      Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+        return Ok(vec![offset..end]);
+      }
+      Err(err) => return Err(seek_error(err)),
+    };
+    if data >= end {
+      break;
+    }
+    let hole = seek(file, data, libc::SEEK_HOLE).map_err(seek_error)?;
+    ranges.push(data..hole.min(end));
+    position = hole;
+  }
+  Ok(ranges)
+}
+
+fn seek(file: &fs::File, offset: u64, whence: libc::c_int) -> io::Result<u64> {
+  use std::os::unix::io::AsRawFd;
+  let ret =
+    unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) };
+  if ret < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(ret as u64)
+}
+
+fn seek_error(source: io::Error) -> RandomAccessError {
+  RandomAccessError::IO {
+    context: Some("Failed to find holes in file".to_string()),
+    return_code: source.raw_os_error(),
+    source,
+  }
+}
+
 /// Linux-specific trimming to sparse files
 #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
-pub async fn trim(
-  file: &mut fs::File,
+pub fn trim(
+  file: &fs::File,
   offset: u64,
   length: u64,
   _block_size: u64,
@@ -51,23 +112,147 @@ pub async fn trim(
   Ok(())
 }
 
+/// Linux-specific reserving of disk blocks, without changing the file length
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub fn preallocate(
+  file: &fs::File,
+  offset: u64,
+  length: u64,
+) -> Result<(), RandomAccessError> {
+  use libc::{fallocate, FALLOC_FL_KEEP_SIZE};
+  use std::os::unix::io::AsRawFd;
+
+  if length == 0 {
+    return Ok(());
+  }
+  let ret = unsafe {
+    fallocate(
+      file.as_raw_fd(),
+      FALLOC_FL_KEEP_SIZE,
+      offset as libc::off_t,
+      length as libc::off_t,
+    )
+  };
+  if ret < 0 {
+    let source = io::Error::last_os_error();
+    // Not supported everywhere, e.g. on NFS or ext3, in which case there's
+    // nothing to do.
+    if crate::trim_mode::is_unsupported(&source) {
+      return Ok(());
+    }
+    return Err(RandomAccessError::IO {
+      context: Some("Failed to preallocate file on linux".to_string()),
+      return_code: Some(ret),
+      source,
+    });
+  }
+  Ok(())
+}
+
+/// Linux-specific zeroing of a range that keeps its blocks allocated,
+/// writing zeros on file systems that can't
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn zero_range(
+  file: &fs::File,
+  offset: u64,
+  length: u64,
+) -> Result<(), RandomAccessError> {
+  use libc::{fallocate, FALLOC_FL_KEEP_SIZE, FALLOC_FL_ZERO_RANGE};
+  use std::os::unix::io::AsRawFd;
+
+  if length == 0 {
+    return Ok(());
+  }
+  let ret = unsafe {
+    fallocate(
+      file.as_raw_fd(),
+      FALLOC_FL_ZERO_RANGE | FALLOC_FL_KEEP_SIZE,
+      offset as libc::off_t,
+      length as libc::off_t,
+    )
+  };
+  if ret < 0 {
+    let source = io::Error::last_os_error();
+    if crate::trim_mode::is_unsupported(&source) {
+      crate::positional::write_zeros(file, offset, length)?;
+      return Ok(());
+    }
+    return Err(RandomAccessError::IO {
+      context: Some("Failed to zero range of file on linux".to_string()),
+      return_code: Some(ret),
+      source,
+    });
+  }
+  Ok(())
+}
+
+/// Zeroing of a range by writing zeros
+#[cfg(any(target_os = "freebsd", target_os = "macos"))]
+pub fn zero_range(
+  file: &fs::File,
+  offset: u64,
+  length: u64,
+) -> Result<(), RandomAccessError> {
+  crate::positional::write_zeros(file, offset, length)?;
+  Ok(())
+}
+
+/// OSX-specific reserving of disk blocks, without changing the file length.
+/// Blocks can only be reserved past the end of what's allocated already, so
+/// this reserves up to `offset + length`.
+#[cfg(target_os = "macos")]
+pub fn preallocate(
+  file: &fs::File,
+  offset: u64,
+  length: u64,
+) -> Result<(), RandomAccessError> {
+  use std::os::unix::fs::MetadataExt;
+  use std::os::unix::io::AsRawFd;
+
+  let allocated = file.metadata()?.blocks() * 512;
+  let end = offset + length;
+  if end <= allocated {
+    return Ok(());
+  }
+  let mut store = libc::fstore_t {
+    // Try contiguous blocks first.
+    fst_flags: libc::F_ALLOCATECONTIG | libc::F_ALLOCATEALL,
+    fst_posmode: libc::F_PEOFPOSMODE,
+    fst_offset: 0,
+    fst_length: (end - allocated) as libc::off_t,
+    fst_bytesalloc: 0,
+  };
+  let fd = file.as_raw_fd();
+  unsafe {
+    let mut ret = libc::fcntl(fd, libc::F_PREALLOCATE, &store);
+    if ret < 0 {
+      store.fst_flags = libc::F_ALLOCATEALL;
+      ret = libc::fcntl(fd, libc::F_PREALLOCATE, &store);
+    }
+    if ret < 0 {
+      let source = io::Error::last_os_error();
+      if crate::trim_mode::is_unsupported(&source) {
+        return Ok(());
+      }
+      return Err(RandomAccessError::IO {
+        context: Some("Failed to preallocate file on macos".to_string()),
+        return_code: Some(ret),
+        source,
+      });
+    }
+  }
+  Ok(())
+}
+
 /// OSX-specific trimming of a file to a sparse file
 #[cfg(target_os = "macos")]
-pub async fn trim(
-  file: &mut fs::File,
+pub fn trim(
+  file: &fs::File,
   offset: u64,
   length: u64,
   block_size: u64,
 ) -> Result<(), RandomAccessError> {
-  #[cfg(feature = "async-std")]
-  use async_std::io::{
-    prelude::{SeekExt, WriteExt},
-    SeekFrom,
-  };
-  #[cfg(feature = "tokio")]
-  use std::io::SeekFrom;
-  #[cfg(feature = "tokio")]
-  use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+  use crate::positional::write_all_at;
 
   if length == 0 {
     return Ok(());
@@ -96,8 +281,7 @@ pub async fn trim(
   if initial_zero_length > 0 {
     // Needs zeroing
     let data = vec![0_u8; initial_zero_length as usize];
-    file.seek(SeekFrom::Start(offset)).await?;
-    file.write_all(&data).await?;
+    write_all_at(file, &data, offset)?;
     if initial_zero_length == length {
       // This was the simple case of zeroing without hole punching
       return Ok(());
@@ -120,8 +304,7 @@ pub async fn trim(
   if last_block_offset < end {
     // Needs zeroing of the last block
     let data = vec![0_u8; (end - last_block_offset) as usize];
-    file.seek(SeekFrom::Start(last_block_offset)).await?;
-    file.write_all(&data).await?;
+    write_all_at(file, &data, last_block_offset)?;
   }
 
   Ok(())
@@ -0,0 +1,350 @@
+//! io_uring backend for Linux, see the `io-uring` feature.
+//!
+//! All storage in the process shares one ring. Operations are pushed to its
+//! submission queue from whichever thread polls them, and a single reaper
+//! thread waits for completions and wakes up the tasks waiting for them.
+//! Nothing runs on the async runtime's blocking thread pool.
+
+use crate::sync_policy::SyncKind;
+use io_uring::{opcode, squeue, types, IoUring};
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+const RING_ENTRIES: u32 = 256;
+// Largest single read or write submitted, reads and writes are split into
+// chunks of at most this size.
+const MAX_CHUNK: usize = 1 << 30;
+
+/// The process-wide ring.
+pub struct Ring {
+  ring: IoUring,
+  // Serializes pushing to the submission queue.
+  submit_lock: Mutex<()>,
+  in_flight: Mutex<HashMap<u64, Arc<Op>>>,
+  next_id: AtomicU64,
+}
+
+impl std::fmt::Debug for Ring {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Ring").finish_non_exhaustive()
+  }
+}
+
+impl Ring {
+  /// Get the process-wide ring, or None if io_uring isn't available, e.g.
+  /// because the kernel is too old or it is blocked by seccomp.
+  pub fn get() -> Option<&'static Ring> {
+    static RING: OnceLock<Option<&'static Ring>> = OnceLock::new();
+    *RING.get_or_init(|| {
+      let ring = match IoUring::new(RING_ENTRIES) {
+        Ok(ring) => ring,
+        Err(err) => {
+          log::debug!("io_uring not available, using thread pool: {}", err);
+          return None;
+        }
+      };
+      let ring: &'static Ring = Box::leak(Box::new(Ring {
+        ring,
+        submit_lock: Mutex::new(()),
+        in_flight: Mutex::new(HashMap::new()),
+        next_id: AtomicU64::new(0),
+      }));
+      std::thread::Builder::new()
+        .name("random-access-disk-io-uring".into())
+        .spawn(move || ring.reap())
+        .ok()?;
+      Some(ring)
+    })
+  }
+
+  /// Read into `buffer` from `offset` until it's full or the end of the file
+  /// is reached. Returns the buffer and how many bytes were read.
+  pub async fn read_at(
+    &'static self,
+    file: &Arc<fs::File>,
+    offset: u64,
+    mut buffer: Vec<u8>,
+  ) -> io::Result<(Vec<u8>, usize)> {
+    let length = buffer.len();
+    let mut filled = 0;
+    while filled < length {
+      let len = (length - filled).min(MAX_CHUNK) as u32;
+      let ptr = buffer[filled..].as_mut_ptr();
+      let entry = opcode::Read::new(types::Fd(file.as_raw_fd()), ptr, len)
+        .offset(offset + filled as u64)
+        .build();
+      // SAFETY: the entry points into `buffer`, which is kept alive and
+      // untouched until the operation completes.
+      let (returned, result) = unsafe { self.run(file, buffer, entry) }.await;
+      buffer = returned;
+      match result {
+        Ok(0) => break,
+        Ok(n) => filled += n as usize,
+        Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+        Err(err) => return Err(err),
+      }
+    }
+    Ok((buffer, filled))
+  }
+
+  /// Write all of `data` at `offset`.
+  pub async fn write_all_at(
+    &'static self,
+    file: &Arc<fs::File>,
+    offset: u64,
+    data: Vec<u8>,
+  ) -> io::Result<()> {
+    let length = data.len();
+    let mut data = data;
+    let mut written = 0;
+    while written < length {
+      let len = (length - written).min(MAX_CHUNK) as u32;
+      let ptr = data[written..].as_ptr();
+      let entry = opcode::Write::new(types::Fd(file.as_raw_fd()), ptr, len)
+        .offset(offset + written as u64)
+        .build();
+      // SAFETY: the entry points into `data`, which is kept alive until the
+      // operation completes.
+      let (returned, result) = unsafe { self.run(file, data, entry) }.await;
+      data = returned;
+      match result {
+        Ok(0) => {
+          return Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "failed to write whole buffer",
+          ))
+        }
+        Ok(n) => written += n as usize,
+        Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+        Err(err) => return Err(err),
+      }
+    }
+    Ok(())
+  }
+
+  /// Sync `file` as `kind` says.
+  pub async fn sync(
+    &'static self,
+    file: &Arc<fs::File>,
+    kind: SyncKind,
+  ) -> io::Result<()> {
+    let flags = match kind {
+      SyncKind::All => types::FsyncFlags::empty(),
+      SyncKind::Data => types::FsyncFlags::DATASYNC,
+    };
+    let entry = opcode::Fsync::new(types::Fd(file.as_raw_fd()))
+      .flags(flags)
+      .build();
+    // SAFETY: no buffers involved.
+    unsafe { self.run(file, vec![], entry) }.await.1.map(|_| ())
+  }
+
+  /// `fallocate` on `file` with `mode`.
+  #[cfg(feature = "sparse")]
+  pub async fn fallocate(
+    &'static self,
+    file: &Arc<fs::File>,
+    offset: u64,
+    length: u64,
+    mode: i32,
+  ) -> io::Result<()> {
+    let entry = opcode::Fallocate::new(types::Fd(file.as_raw_fd()), length)
+      .offset(offset)
+      .mode(mode)
+      .build();
+    // SAFETY: no buffers involved.
+    unsafe { self.run(file, vec![], entry) }.await.1.map(|_| ())
+  }
+
+  /// Submit `entry` and wait for it to complete, returning `buffer` and the
+  /// result. Fails without running `entry` only if it can't be queued.
+  ///
+  /// # Safety
+  ///
+  /// Any memory `entry` points to must be inside `buffer`'s allocation, and
+  /// any file descriptor must be `file`'s. Both are kept alive until the
+  /// operation completes, even if the returned future is dropped before.
+  unsafe fn run(
+    &'static self,
+    file: &Arc<fs::File>,
+    buffer: Vec<u8>,
+    entry: squeue::Entry,
+  ) -> OpFuture {
+    let op = Arc::new(Op {
+      state: Mutex::new(OpState {
+        result: None,
+        waker: None,
+        buffer,
+        _file: file.clone(),
+      }),
+    });
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    self.in_flight.lock().unwrap().insert(id, op.clone());
+    OpFuture {
+      ring: self,
+      id,
+      op,
+      entry: Some(entry.user_data(id)),
+      unsubmitted: false,
+    }
+  }
+
+  /// Push `entry` to the submission queue. Returns false if it's full and
+  /// the kernel too busy to make room for now.
+  ///
+  /// # Safety
+  ///
+  /// As for [Ring::run].
+  unsafe fn push(&self, entry: &squeue::Entry) -> io::Result<bool> {
+    let _guard = self.submit_lock.lock().unwrap();
+    let mut queue = self.ring.submission_shared();
+    if queue.push(entry).is_err() {
+      // Full: hand what's queued over to the kernel to make room.
+      queue.sync();
+      match self.ring.submit() {
+        Ok(_) => {}
+        Err(err) if is_busy(&err) => return Ok(false),
+        Err(err) => return Err(err),
+      }
+      queue.sync();
+      if queue.push(entry).is_err() {
+        return Ok(false);
+      }
+    }
+    queue.sync();
+    Ok(true)
+  }
+
+  /// Wait for completions and hand them to their operations, forever.
+  fn reap(&self) {
+    loop {
+      // Completions are reaped even if this fails, as that's what makes
+      // room when the completion queue is full.
+      if let Err(err) = self.ring.submit_and_wait(1) {
+        if !is_busy(&err) {
+          log::warn!("io_uring wait failed: {}", err);
+          thread::sleep(Duration::from_millis(1));
+        }
+      }
+      // SAFETY: this is the only thread that touches the completion queue.
+      for entry in unsafe { self.ring.completion_shared() } {
+        let op = self.in_flight.lock().unwrap().remove(&entry.user_data());
+        if let Some(op) = op {
+          let mut state = op.state.lock().unwrap();
+          state.result = Some(entry.result());
+          if let Some(waker) = state.waker.take() {
+            waker.wake();
+          }
+        }
+      }
+    }
+  }
+}
+
+/// Whether submitting failed only for now, e.g. because the completion
+/// queue is full until the reaper catches up.
+fn is_busy(err: &io::Error) -> bool {
+  matches!(
+    err.raw_os_error(),
+    Some(libc::EAGAIN | libc::EBUSY | libc::EINTR)
+  )
+}
+
+#[derive(Debug)]
+struct Op {
+  state: Mutex<OpState>,
+}
+
+#[derive(Debug)]
+struct OpState {
+  result: Option<i32>,
+  waker: Option<Waker>,
+  buffer: Vec<u8>,
+  _file: Arc<fs::File>,
+}
+
+/// Future of [Ring::run]. Queues the entry when first polled, and then
+/// waits for it to complete. While the kernel is too busy to take it, the
+/// task yields and tries again rather than blocking its thread.
+#[derive(Debug)]
+struct OpFuture {
+  ring: &'static Ring,
+  id: u64,
+  op: Arc<Op>,
+  // The entry until it's queued.
+  entry: Option<squeue::Entry>,
+  // Whether the entry is queued but might not be submitted yet. The reaper
+  // only submits again once something completes, so this can't be left to
+  // it.
+  unsubmitted: bool,
+}
+
+impl Future for OpFuture {
+  type Output = (Vec<u8>, io::Result<u32>);
+
+  fn poll(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Self::Output> {
+    let this = &mut *self;
+    if let Some(entry) = this.entry.take() {
+      // SAFETY: as promised to Ring::run.
+      match unsafe { this.ring.push(&entry) } {
+        Ok(true) => this.unsubmitted = true,
+        Ok(false) => {
+          this.entry = Some(entry);
+          cx.waker().wake_by_ref();
+          return Poll::Pending;
+        }
+        Err(err) => {
+          // Never queued, so nothing uses the buffer any more.
+          this.ring.in_flight.lock().unwrap().remove(&this.id);
+          let buffer =
+            std::mem::take(&mut this.op.state.lock().unwrap().buffer);
+          return Poll::Ready((buffer, Err(err)));
+        }
+      }
+    }
+
+    let mut state = this.op.state.lock().unwrap();
+    if let Some(result) = state.result {
+      let buffer = std::mem::take(&mut state.buffer);
+      let result = if result < 0 {
+        Err(io::Error::from_raw_os_error(-result))
+      } else {
+        Ok(result as u32)
+      };
+      return Poll::Ready((buffer, result));
+    }
+    if this.unsubmitted {
+      // Queued entries are submitted by whoever submits next, so failing
+      // here would leave the kernel to run it after all. Try again instead.
+      if let Err(err) = this.ring.ring.submit() {
+        log::debug!("io_uring submit failed, retrying: {}", err);
+        cx.waker().wake_by_ref();
+        return Poll::Pending;
+      }
+      this.unsubmitted = false;
+    }
+    state.waker = Some(cx.waker().clone());
+    Poll::Pending
+  }
+}
+
+impl Drop for OpFuture {
+  fn drop(&mut self) {
+    // Never queued, so it would never complete to be removed.
+    if self.entry.is_some() {
+      self.ring.in_flight.lock().unwrap().remove(&self.id);
+    }
+  }
+}
@@ -1,25 +1,54 @@
 use random_access_storage::RandomAccessError;
+use std::fs;
+use std::ops::Range;
 use std::os::windows::prelude::{AsRawHandle, RawHandle};
+use std::path::Path;
 
 use winapi::shared::minwindef::{DWORD, LPVOID};
 use winapi::um::ioapiset::DeviceIoControl;
+use winapi::um::winioctl::FSCTL_QUERY_ALLOCATED_RANGES;
 use winapi::um::winioctl::FSCTL_SET_SPARSE;
 use winapi::um::winioctl::FSCTL_SET_ZERO_DATA;
 
-#[cfg(feature = "async-std")]
-use async_std::fs;
-#[cfg(feature = "tokio")]
-use tokio::fs;
-
-pub async fn get_length_and_block_size(
+pub fn get_length_and_block_size(
   file: &fs::File,
 ) -> Result<(u64, u64), RandomAccessError> {
-  let meta = file.metadata().await?;
+  let meta = file.metadata()?;
   Ok((meta.len(), 0))
 }
 
+/// Get the space the file takes on disk, which is less than its length
+/// where it has holes or is compressed
+pub fn allocated_size(
+  _file: &fs::File,
+  path: &Path,
+) -> Result<u64, RandomAccessError> {
+  use std::os::windows::ffi::OsStrExt;
+  use winapi::um::fileapi::{GetCompressedFileSizeW, INVALID_FILE_SIZE};
+
+  let path: Vec<u16> = path
+    .as_os_str()
+    .encode_wide()
+    .chain(std::iter::once(0))
+    .collect();
+  let mut high: DWORD = 0;
+  let low = unsafe { GetCompressedFileSizeW(path.as_ptr(), &mut high) };
+  if low == INVALID_FILE_SIZE {
+    // Also a valid low half, unless there's an error.
+    let source = std::io::Error::last_os_error();
+    if source.raw_os_error() != Some(0) {
+      return Err(RandomAccessError::IO {
+        context: Some("GetCompressedFileSizeW failed on windows".to_string()),
+        return_code: source.raw_os_error(),
+        source,
+      });
+    }
+  }
+  Ok(((high as u64) << 32) | low as u64)
+}
+
 /// Set file to sparse
-pub async fn set_sparse(file: &mut fs::File) -> Result<(), RandomAccessError> {
+pub fn set_sparse(file: &mut fs::File) -> Result<(), RandomAccessError> {
   unsafe {
     device_io_control(
       file.as_raw_handle(),
@@ -33,8 +62,19 @@ pub async fn set_sparse(file: &mut fs::File) -> Result<(), RandomAccessError> {
   Ok(())
 }
 
+/// Zeroing of a range by writing zeros, as `FSCTL_SET_ZERO_DATA` would
+/// deallocate it in sparse files
+pub fn zero_range(
+  file: &fs::File,
+  offset: u64,
+  length: u64,
+) -> Result<(), RandomAccessError> {
+  crate::positional::write_zeros(file, offset, length)?;
+  Ok(())
+}
+
 /// Windows-specific trimming of a file to a sparse file
-pub async fn trim(
+pub fn trim(
   file: &fs::File,
   offset: u64,
   length: u64,
@@ -55,6 +95,119 @@ pub async fn trim(
   Ok(())
 }
 
+/// Ranges of `length` bytes at `offset` that hold data rather than holes,
+/// found with `FSCTL_QUERY_ALLOCATED_RANGES`.
+pub fn data_ranges(
+  file: &fs::File,
+  offset: u64,
+  length: u64,
+) -> Result<Vec<Range<u64>>, RandomAccessError> {
+  // Not in winapi's default features.
+  const ERROR_MORE_DATA: i32 = 234;
+
+  let end = offset + length;
+  let mut ranges = vec![];
+  let mut position = offset;
+  let mut buffer = [FileAllocatedRangeBuffer {
+    offset: 0,
+    length: 0,
+  }; 64];
+  while position < end {
+    let query = FileAllocatedRangeBuffer {
+      offset: position,
+      length: end - position,
+    };
+    let mut returned_bytes: DWORD = 0;
+    let ret = unsafe {
+      DeviceIoControl(
+        file.as_raw_handle() as _,
+        FSCTL_QUERY_ALLOCATED_RANGES,
+        &query as *const _ as LPVOID,
+        std::mem::size_of_val(&query) as DWORD,
+        buffer.as_mut_ptr() as LPVOID,
+        std::mem::size_of_val(&buffer) as DWORD,
+        &mut returned_bytes,
+        std::ptr::null_mut(),
+      )
+    };
+    let more = if ret == 0 {
+      let source = std::io::Error::last_os_error();
+      if source.raw_os_error() != Some(ERROR_MORE_DATA) {
+        return Err(RandomAccessError::IO {
+          context: Some("Failed to query allocated ranges on windows".into()),
+          return_code: Some(ret),
+          source,
+        });
+      }
+      true
+    } else {
+      false
+    };
+
+    let count =
+      returned_bytes as usize / std::mem::size_of::<FileAllocatedRangeBuffer>();
+    for range in &buffer[..count] {
+      let start = range.offset.max(position);
+      let range_end = (range.offset + range.length).min(end);
+      if start < range_end {
+        ranges.push(start..range_end);
+      }
+    }
+    match buffer[..count].last() {
+      Some(last) if more => position = last.offset + last.length,
+      _ => break,
+    }
+  }
+  Ok(ranges)
+}
+
+/// Windows-specific reserving of disk blocks, without changing the file
+/// length. Only the allocation size of the whole file can be set, so this
+/// reserves up to `offset + length`.
+pub fn preallocate(
+  file: &fs::File,
+  offset: u64,
+  length: u64,
+) -> Result<(), RandomAccessError> {
+  use winapi::um::fileapi::{SetFileInformationByHandle, FILE_ALLOCATION_INFO};
+  use winapi::um::minwinbase::FileAllocationInfo;
+
+  // A smaller allocation size than the length would truncate the file.
+  let end = offset + length;
+  if end <= file.metadata()?.len() {
+    return Ok(());
+  }
+  let mut info: FILE_ALLOCATION_INFO = unsafe { std::mem::zeroed() };
+  unsafe {
+    *info.AllocationSize.QuadPart_mut() = end as i64;
+    let ret = SetFileInformationByHandle(
+      file.as_raw_handle() as _,
+      FileAllocationInfo,
+      &mut info as *mut _ as LPVOID,
+      std::mem::size_of::<FILE_ALLOCATION_INFO>() as DWORD,
+    );
+    if ret == 0 {
+      let source = std::io::Error::last_os_error();
+      if crate::trim_mode::is_unsupported(&source) {
+        return Ok(());
+      }
+      return Err(RandomAccessError::IO {
+        context: Some("Failed to preallocate file on windows".to_string()),
+        return_code: Some(ret),
+        source,
+      });
+    }
+  }
+  Ok(())
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FileAllocatedRangeBuffer {
+  offset: u64,
+  length: u64,
+}
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 struct FileZeroDataInformation {
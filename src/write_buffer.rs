@@ -0,0 +1,158 @@
+use crate::batch::Run;
+use std::collections::BTreeMap;
+
+/// Writes kept in memory until flushed, see [crate::Builder::write_buffer].
+///
+/// Overlapping and adjacent writes are merged, so the buffered ranges never
+/// touch each other.
+#[derive(Debug)]
+pub struct WriteBuffer {
+  // Start offset to data of each range.
+  ranges: BTreeMap<u64, Vec<u8>>,
+  size: usize,
+  capacity: usize,
+  // Length of the file on disk, which lags behind while writes past it are
+  // buffered.
+  file_length: u64,
+}
+
+impl WriteBuffer {
+  pub fn new(capacity: usize, file_length: u64) -> Self {
+    Self {
+      ranges: BTreeMap::new(),
+      size: 0,
+      capacity,
+      file_length,
+    }
+  }
+
+  pub fn file_length(&self) -> u64 {
+    self.file_length
+  }
+
+  /// Whether there are no buffered writes, and the file on disk is at least
+  /// `length` long.
+  pub fn is_clean(&self, length: u64) -> bool {
+    self.ranges.is_empty() && self.file_length >= length
+  }
+
+  /// Whether the buffer has grown enough to be flushed.
+  pub fn is_full(&self) -> bool {
+    self.size >= self.capacity
+  }
+
+  /// Buffer `data` at `offset`.
+  pub fn insert(&mut self, offset: u64, data: &[u8]) {
+    if data.is_empty() {
+      return;
+    }
+    let end = offset + data.len() as u64;
+    let touching: Vec<u64> = self
+      .ranges
+      .range(..=end)
+      .rev()
+      .take_while(|(start, range)| *start + range.len() as u64 >= offset)
+      .map(|(start, _)| *start)
+      .collect();
+
+    // Common case of overwriting part of one range.
+    if let [start] = touching[..] {
+      let range = self.ranges.get_mut(&start).unwrap();
+      if start <= offset && start + range.len() as u64 >= end {
+        let from = (offset - start) as usize;
+        range[from..from + data.len()].copy_from_slice(data);
+        return;
+      }
+    }
+
+    let start = touching.last().map_or(offset, |&start| start.min(offset));
+    let mut merged_end = end;
+    for &touching_start in &touching {
+      let range_end =
+        touching_start + self.ranges[&touching_start].len() as u64;
+      merged_end = merged_end.max(range_end);
+    }
+    let mut merged = vec![0; (merged_end - start) as usize];
+    for touching_start in touching {
+      let range = self.ranges.remove(&touching_start).unwrap();
+      self.size -= range.len();
+      let from = (touching_start - start) as usize;
+      merged[from..from + range.len()].copy_from_slice(&range);
+    }
+    let from = (offset - start) as usize;
+    merged[from..from + data.len()].copy_from_slice(data);
+    self.size += merged.len();
+    self.ranges.insert(start, merged);
+  }
+
+  /// Whether `length` bytes at `offset` are all buffered.
+  pub fn covers(&self, offset: u64, length: u64) -> bool {
+    if length == 0 {
+      return true;
+    }
+    match self.ranges.range(..=offset).next_back() {
+      Some((start, range)) => start + range.len() as u64 >= offset + length,
+      None => false,
+    }
+  }
+
+  /// Copy the buffered bytes of the range at `offset` over `buffer`.
+  pub fn overlay(&self, offset: u64, buffer: &mut [u8]) {
+    let end = offset + buffer.len() as u64;
+    for (&start, range) in self
+      .ranges
+      .range(..end)
+      .rev()
+      .take_while(|(start, range)| *start + range.len() as u64 > offset)
+    {
+      let from = start.max(offset);
+      let to = (start + range.len() as u64).min(end);
+      buffer[(from - offset) as usize..(to - offset) as usize].copy_from_slice(
+        &range[(from - start) as usize..(to - start) as usize],
+      );
+    }
+  }
+
+  /// Copies of all buffered writes, to be written to the file. They stay
+  /// buffered until [WriteBuffer::flushed], so that they aren't lost if
+  /// writing them fails.
+  pub fn runs(&self) -> Vec<Run> {
+    self
+      .ranges
+      .iter()
+      .map(|(&offset, data)| Run {
+        offset,
+        end: offset + data.len() as u64,
+        chunks: vec![data.clone()],
+      })
+      .collect()
+  }
+
+  /// Record that the buffered writes were written to the file, which is now
+  /// `length` long, and drop them.
+  pub fn flushed(&mut self, length: u64) {
+    self.ranges.clear();
+    self.size = 0;
+    self.file_length = length;
+  }
+
+  /// Take out all buffered writes, to be written to a file that will then be
+  /// `length` long.
+  pub fn take(&mut self, length: u64) -> Vec<Run> {
+    self.size = 0;
+    self.file_length = length;
+    std::mem::take(&mut self.ranges)
+      .into_iter()
+      .map(|(offset, data)| Run {
+        offset,
+        end: offset + data.len() as u64,
+        chunks: vec![data],
+      })
+      .collect()
+  }
+
+  /// Record that the file on disk is now `length` long.
+  pub fn set_file_length(&mut self, length: u64) {
+    self.file_length = length;
+  }
+}
@@ -0,0 +1,70 @@
+use rad::blocking::RandomAccessDisk;
+use random_access_disk as rad;
+use tempfile::Builder;
+
+#[test]
+fn can_write_read_del_and_truncate() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let mut file = RandomAccessDisk::open(dir.path().join("1.db")).unwrap();
+  assert!(file.is_empty().unwrap());
+  file.write(0, b"hello").unwrap();
+  file.write(5, b" world").unwrap();
+  assert_eq!(file.read(0, 11).unwrap(), b"hello world");
+  file.del(5, 2).unwrap();
+  assert_eq!(file.read(0, 11).unwrap(), b"hello\0\0orld");
+  file.write_batch(&[(0, b"j"), (11, b"!")]).unwrap();
+  let mut buffer = [0; 5];
+  file.read_into(0, &mut buffer).unwrap();
+  assert_eq!(&buffer, b"jello");
+  file.truncate(2).unwrap();
+  assert_eq!(file.len().unwrap(), 2);
+  file.close().unwrap();
+  assert_eq!(std::fs::read(dir.path().join("1.db")).unwrap(), b"je");
+}
+
+#[test]
+fn takes_builder_options() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("2.db");
+  let mut file = RandomAccessDisk::builder(&path)
+    .write_buffer(100)
+    .read_cache(4096)
+    .build_blocking()
+    .unwrap();
+  file.write(0, b"hello").unwrap();
+  assert_eq!(std::fs::read(&path).unwrap(), b"");
+  assert_eq!(file.read(0, 5).unwrap(), b"hello");
+  assert_eq!(file.read_cache_stats().unwrap().misses, 1);
+  drop(file);
+
+  let mut file = RandomAccessDisk::builder(&path)
+    .read_only(true)
+    .build_blocking()
+    .unwrap();
+  assert_eq!(file.read(0, 5).unwrap(), b"hello");
+  let err = file.write(0, b"bye").unwrap_err();
+  assert!(matches!(
+    rad::DiskError::from_random_access_error(&err),
+    Some(rad::DiskError::ReadOnly)
+  ));
+}
+
+#[test]
+fn works_within_async_runtime() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("3.db");
+  tokio::runtime::Runtime::new().unwrap().block_on(async {
+    let mut file = RandomAccessDisk::open(&path).unwrap();
+    file.write(0, b"hello").unwrap();
+    assert_eq!(file.read(0, 5).unwrap(), b"hello");
+  });
+}
@@ -0,0 +1,24 @@
+/// Run an async test, with `#[apply(async_test!)]`, on the executor of the
+/// runtime feature that's enabled: tokio, else smol, else async-std.
+macro_rules! async_test {
+  ($(#[$attr:meta])* async fn $name:ident() $body:block) => {
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    $(#[$attr])*
+    async fn $name() $body
+
+    #[cfg(all(feature = "smol", not(feature = "tokio")))]
+    #[test]
+    $(#[$attr])*
+    fn $name() {
+      smol::block_on(async $body)
+    }
+
+    #[cfg(not(any(feature = "tokio", feature = "smol")))]
+    #[async_std::test]
+    $(#[$attr])*
+    async fn $name() $body
+  };
+}
+
+pub(crate) use async_test;
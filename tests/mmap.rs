@@ -0,0 +1,128 @@
+#![cfg(feature = "mmap")]
+
+use rad::RandomAccessBatch;
+use random_access_disk as rad;
+use random_access_storage::RandomAccess;
+use tempfile::Builder;
+
+mod common;
+
+use common::async_test;
+use macro_rules_attribute::apply;
+
+#[apply(async_test!)]
+async fn can_write_read_del_and_truncate() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let mut file = rad::RandomAccessMmap::open(dir.path().join("1.db"))
+    .await
+    .unwrap();
+  assert!(file.is_empty().await.unwrap());
+  file.write(0, b"hello").await.unwrap();
+  file.write(5, b" world").await.unwrap();
+  assert_eq!(file.read(0, 11).await.unwrap(), b"hello world");
+  assert!(file.read(5, 10).await.is_err());
+  let mut buffer = [0; 5];
+  file.read_into(6, &mut buffer).await.unwrap();
+  assert_eq!(&buffer, b"world");
+  file.del(5, 2).await.unwrap();
+  assert_eq!(file.read(0, 11).await.unwrap(), b"hello\0\0orld");
+  file.write(20, b"").await.unwrap();
+  assert_eq!(file.len().await.unwrap(), 20);
+  assert_eq!(file.read(11, 9).await.unwrap(), [0; 9]);
+  file.del(8, 100).await.unwrap();
+  assert_eq!(file.len().await.unwrap(), 8);
+  file.truncate(2).await.unwrap();
+  file.truncate(4).await.unwrap();
+  assert_eq!(file.read(0, 4).await.unwrap(), b"he\0\0");
+  file.close().await.unwrap();
+  assert_eq!(std::fs::read(dir.path().join("1.db")).unwrap(), b"he\0\0");
+}
+
+#[apply(async_test!)]
+async fn can_del_across_blocks() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let mut file = rad::RandomAccessMmap::open(dir.path().join("2.db"))
+    .await
+    .unwrap();
+  let data = vec![1; 3 * 4096 * 4];
+  file.write(0, &data).await.unwrap();
+  file.del(100, 2 * 4096 * 4).await.unwrap();
+  let read = file.read(0, data.len() as u64).await.unwrap();
+  assert_eq!(&read[..100], &data[..100]);
+  assert!(read[100..100 + 2 * 4096 * 4].iter().all(|&b| b == 0));
+  assert_eq!(&read[100 + 2 * 4096 * 4..], &data[100 + 2 * 4096 * 4..]);
+}
+
+#[apply(async_test!)]
+async fn interchangeable_with_disk() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let mut file = rad::RandomAccessDisk::open(dir.path().join("3.db"))
+    .await
+    .unwrap();
+  file.write(0, b"hello world").await.unwrap();
+  file.close().await.unwrap();
+
+  let mut file = rad::RandomAccessMmap::open(dir.path().join("3.db"))
+    .await
+    .unwrap();
+  assert_eq!(file.read(0, 11).await.unwrap(), b"hello world");
+  file.write(6, b"there").await.unwrap();
+  file.close().await.unwrap();
+
+  let mut file = rad::RandomAccessDisk::open(dir.path().join("3.db"))
+    .await
+    .unwrap();
+  assert_eq!(file.read(0, 11).await.unwrap(), b"hello there");
+}
+
+#[apply(async_test!)]
+async fn can_open_read_only() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let mut file = rad::RandomAccessMmap::open(dir.path().join("4.db"))
+    .await
+    .unwrap();
+  file.write(0, b"hello world").await.unwrap();
+  drop(file);
+
+  let mut file = rad::RandomAccessDisk::builder(dir.path().join("4.db"))
+    .read_only(true)
+    .build_mmap()
+    .await
+    .unwrap();
+  assert_eq!(file.read(0, 11).await.unwrap(), b"hello world");
+  let err = file.write(0, b"bye").await.unwrap_err();
+  assert!(matches!(
+    rad::DiskError::from_random_access_error(&err),
+    Some(rad::DiskError::ReadOnly)
+  ));
+}
+
+#[apply(async_test!)]
+async fn manual_sync_policy() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let mut file = rad::RandomAccessDisk::builder(dir.path().join("5.db"))
+    .sync_policy(rad::SyncPolicy::Manual)
+    .build_mmap()
+    .await
+    .unwrap();
+  file.write(0, b"hello").await.unwrap();
+  file.write(0, b"j").await.unwrap();
+  file.sync_all().await.unwrap();
+  drop(file);
+  assert_eq!(std::fs::read(dir.path().join("5.db")).unwrap(), b"jello");
+}
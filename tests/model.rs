@@ -46,13 +46,21 @@ proptest! {
   })]
 
   #[test]
-  #[cfg(feature = "async-std")]
+  #[cfg(not(any(feature = "tokio", feature = "smol")))]
   fn implementation_matches_model(ops: Vec<Op>) {
     assert!(async_std::task::block_on(async {
        assert_implementation_matches_model(ops).await
     }));
   }
 
+  #[test]
+  #[cfg(all(feature = "smol", not(feature = "tokio")))]
+  fn implementation_matches_model(ops: Vec<Op>) {
+    assert!(smol::block_on(async {
+      assert_implementation_matches_model(ops).await
+    }));
+  }
+
   #[test]
   #[cfg(feature = "tokio")]
   fn implementation_matches_model(ops: Vec<Op>) {
@@ -69,12 +77,40 @@ async fn assert_implementation_matches_model(ops: Vec<Op>) -> bool {
     .tempdir()
     .unwrap();
 
-  let mut implementation = RandomAccessDisk::open(dir.path().join("1.db"))
+  let unbuffered = RandomAccessDisk::open(dir.path().join("1.db"))
+    .await
+    .unwrap();
+  // Small enough to be flushed every now and then.
+  let buffered = RandomAccessDisk::builder(dir.path().join("2.db"))
+    .write_buffer(10000)
+    .build()
     .await
     .unwrap();
+  // Small enough to evict blocks every now and then.
+  let cached = RandomAccessDisk::builder(dir.path().join("3.db"))
+    .read_cache(16384)
+    .read_cache_block_size(512)
+    .build()
+    .await
+    .unwrap();
+  for implementation in [unbuffered, buffered, cached] {
+    check_ops(implementation, &ops).await;
+  }
+  #[cfg(feature = "mmap")]
+  check_ops(
+    random_access_disk::RandomAccessMmap::open(dir.path().join("4.db"))
+      .await
+      .unwrap(),
+    &ops,
+  )
+  .await;
+  true
+}
+
+async fn check_ops(mut implementation: impl RandomAccess, ops: &[Op]) {
   let mut model = vec![];
 
-  for op in ops {
+  for op in ops.iter().cloned() {
     match op {
       Read { offset, length } => {
         let end = offset + length;
@@ -117,6 +153,6 @@ async fn assert_implementation_matches_model(ops: Vec<Op>) -> bool {
         }
       }
     }
+    assert_eq!(implementation.len().await.unwrap(), model.len() as u64);
   }
-  true
 }
@@ -3,14 +3,15 @@ use random_access_storage::RandomAccess;
 use std::env;
 use tempfile::Builder;
 
-#[cfg(feature = "async-std")]
-use async_std::test as async_test;
-#[cfg(feature = "tokio")]
-use tokio::test as async_test;
+mod common;
 
-#[async_test]
+use common::async_test;
+use macro_rules_attribute::apply;
+
+#[apply(async_test!)]
 // postmortem: read_exact wasn't behaving like we hoped, so we had to switch
-// back to `.read()` and disable clippy for that rule specifically.
+// back to `.read()` and disable clippy for that rule specifically. Reads now
+// loop until full, and an empty write past the end grows the file on disk.
 async fn regress_1() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -24,7 +25,7 @@ async fn regress_1() {
   file.read(13, 5).await.unwrap();
 }
 
-#[async_test]
+#[apply(async_test!)]
 // postmortem: accessing the same file twice would fail, so we had to switch to
 // from `.create_new()` to `.create()`.
 //
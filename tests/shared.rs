@@ -0,0 +1,160 @@
+use random_access_disk as rad;
+use tempfile::Builder;
+
+mod common;
+
+use common::async_test;
+use macro_rules_attribute::apply;
+
+#[cfg(not(any(feature = "tokio", feature = "smol")))]
+async fn spawn<T: Send + 'static>(
+  future: impl std::future::Future<Output = T> + Send + 'static,
+) -> T {
+  async_std::task::spawn(future).await
+}
+#[cfg(all(feature = "smol", not(feature = "tokio")))]
+async fn spawn<T: Send + 'static>(
+  future: impl std::future::Future<Output = T> + Send + 'static,
+) -> T {
+  smol::spawn(future).await
+}
+#[cfg(feature = "tokio")]
+async fn spawn<T: Send + 'static>(
+  future: impl std::future::Future<Output = T> + Send + 'static,
+) -> T {
+  tokio::spawn(future).await.unwrap()
+}
+
+#[apply(async_test!)]
+async fn clones_share_the_file() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let file = rad::RandomAccessDisk::builder(dir.path().join("1.db"))
+    .build_shared()
+    .await
+    .unwrap();
+  let other = file.clone();
+  file.write(0, b"hello").await.unwrap();
+  other.write(5, b" world").await.unwrap();
+  assert_eq!(other.read(0, 11).await.unwrap(), b"hello world");
+  assert_eq!(file.len().await.unwrap(), 11);
+  other.del(0, 6).await.unwrap();
+  assert_eq!(file.read(0, 11).await.unwrap(), b"\0\0\0\0\0\0world");
+  file.truncate(5).await.unwrap();
+  assert_eq!(other.len().await.unwrap(), 5);
+  assert!(other.read(0, 6).await.is_err());
+}
+
+#[apply(async_test!)]
+async fn can_read_and_write_from_many_tasks() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let file = rad::RandomAccessDisk::builder(dir.path().join("2.db"))
+    .build_shared()
+    .await
+    .unwrap();
+
+  let mut writers = vec![];
+  for i in 0..32_u8 {
+    let file = file.clone();
+    writers.push(spawn(async move {
+      file.write(i as u64 * 4, &[i; 4]).await.unwrap();
+    }));
+  }
+  for writer in writers {
+    writer.await;
+  }
+  assert_eq!(file.len().await.unwrap(), 128);
+
+  let mut readers = vec![];
+  for i in 0..32_u8 {
+    let file = file.clone();
+    readers.push(spawn(async move {
+      assert_eq!(file.read(i as u64 * 4, 4).await.unwrap(), [i; 4]);
+    }));
+  }
+  for reader in readers {
+    reader.await;
+  }
+}
+
+#[apply(async_test!)]
+async fn overlapping_writes_land_in_order() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let file = rad::RandomAccessDisk::builder(dir.path().join("3.db"))
+    .build_shared()
+    .await
+    .unwrap();
+  let first = file.write(0, &[1; 4096]);
+  let second = file.write(2048, &[2; 4096]);
+  let read = file.read(0, 6144);
+  let (first, second, read) = futures_join(first, second, read).await;
+  first.unwrap();
+  second.unwrap();
+  let data = read.unwrap();
+  assert_eq!(&data[..2048], &[1; 2048][..]);
+  assert_eq!(&data[2048..], &[2; 4096][..]);
+}
+
+#[apply(async_test!)]
+async fn sync_catches_up_after_failed_change() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let file = rad::RandomAccessDisk::builder(dir.path().join("4.db"))
+    .drop_strategy(rad::DropStrategy::DebugPanic)
+    .sync_policy(rad::SyncPolicy::Manual)
+    .build_shared()
+    .await
+    .unwrap();
+  file.write(0, b"hello").await.unwrap();
+  // Past the largest offset files can have.
+  assert!(file.write(i64::MAX as u64, b"!").await.is_err());
+  file.sync_all().await.unwrap();
+  // Would panic with changes left unsynced.
+  drop(file);
+}
+
+/// Poll three futures concurrently, in order, until all are done.
+async fn futures_join<A, B, C>(
+  a: impl std::future::Future<Output = A>,
+  b: impl std::future::Future<Output = B>,
+  c: impl std::future::Future<Output = C>,
+) -> (A, B, C) {
+  use std::pin::pin;
+  use std::task::Poll;
+
+  let (mut a, mut b, mut c) = (pin!(a), pin!(b), pin!(c));
+  let (mut ra, mut rb, mut rc) = (None, None, None);
+  std::future::poll_fn(|cx| {
+    if ra.is_none() {
+      if let Poll::Ready(v) = a.as_mut().poll(cx) {
+        ra = Some(v);
+      }
+    }
+    if rb.is_none() {
+      if let Poll::Ready(v) = b.as_mut().poll(cx) {
+        rb = Some(v);
+      }
+    }
+    if rc.is_none() {
+      if let Poll::Ready(v) = c.as_mut().poll(cx) {
+        rc = Some(v);
+      }
+    }
+    if ra.is_some() && rb.is_some() && rc.is_some() {
+      Poll::Ready((ra.take().unwrap(), rb.take().unwrap(), rc.take().unwrap()))
+    } else {
+      Poll::Pending
+    }
+  })
+  .await
+}
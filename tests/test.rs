@@ -1,14 +1,15 @@
+use rad::RandomAccessBatch;
 use random_access_disk as rad;
 use random_access_storage::RandomAccess;
 use std::io::Read;
 use tempfile::Builder;
 
-#[cfg(feature = "async-std")]
-use async_std::test as async_test;
-#[cfg(feature = "tokio")]
-use tokio::test as async_test;
+mod common;
 
-#[async_test]
+use common::async_test;
+use macro_rules_attribute::apply;
+
+#[apply(async_test!)]
 async fn can_call_new() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -19,7 +20,7 @@ async fn can_call_new() {
     .unwrap();
 }
 
-#[async_test]
+#[apply(async_test!)]
 async fn can_open_buffer() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -31,7 +32,7 @@ async fn can_open_buffer() {
   file.write(0, b"hello").await.unwrap();
 }
 
-#[async_test]
+#[apply(async_test!)]
 async fn can_write() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -44,7 +45,7 @@ async fn can_write() {
   file.write(5, b" world").await.unwrap();
 }
 
-#[async_test]
+#[apply(async_test!)]
 async fn can_read() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -59,7 +60,7 @@ async fn can_read() {
   assert_eq!(String::from_utf8(text.to_vec()).unwrap(), "hello world");
 }
 
-#[async_test]
+#[apply(async_test!)]
 async fn can_truncate_lt() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -82,7 +83,7 @@ async fn can_truncate_lt() {
   assert_eq!(c_contents, "hello w");
 }
 
-#[async_test]
+#[apply(async_test!)]
 async fn can_truncate_gt() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -108,7 +109,7 @@ async fn can_truncate_gt() {
   assert_eq!(c_contents, "hello world\0\0\0\0");
 }
 
-#[async_test]
+#[apply(async_test!)]
 async fn can_truncate_eq() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -131,7 +132,7 @@ async fn can_truncate_eq() {
   assert_eq!(c_contents, "hello world");
 }
 
-#[async_test]
+#[apply(async_test!)]
 async fn can_len() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -151,7 +152,7 @@ async fn can_len() {
   assert_eq!(file.len().await.unwrap(), 8);
 }
 
-#[async_test]
+#[apply(async_test!)]
 async fn can_is_empty() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -173,8 +174,7 @@ async fn can_is_empty() {
   assert!(!file.is_empty().await.unwrap());
 }
 
-#[async_test]
-#[cfg(feature = "async-std")]
+#[apply(async_test!)]
 async fn explicit_no_auto_sync() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -200,7 +200,7 @@ async fn explicit_no_auto_sync() {
   assert_eq!(c_contents, "hello world");
 }
 
-#[async_test]
+#[apply(async_test!)]
 async fn auto_sync() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -224,7 +224,7 @@ async fn auto_sync() {
   assert_eq!(c_contents, "hello world");
 }
 
-#[async_test]
+#[apply(async_test!)]
 async fn auto_sync_with_sync_call() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -249,7 +249,7 @@ async fn auto_sync_with_sync_call() {
   assert_eq!(c_contents, "hello world");
 }
 
-#[async_test]
+#[apply(async_test!)]
 async fn can_del_short() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -271,7 +271,7 @@ async fn can_del_short() {
   assert_eq!(String::from_utf8(people.to_vec()).unwrap(), "people");
 }
 
-#[async_test]
+#[apply(async_test!)]
 async fn can_del_long_middle() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -315,7 +315,7 @@ async fn can_del_long_middle() {
   assert_eq!(String::from_utf8(to.to_vec()).unwrap(), "to");
 }
 
-#[async_test]
+#[apply(async_test!)]
 async fn can_del_long_exact_block() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -337,7 +337,7 @@ async fn can_del_long_exact_block() {
   assert_eq!(0, file.len().await.unwrap());
 }
 
-#[async_test]
+#[apply(async_test!)]
 async fn can_del_long_more_than_block() {
   let dir = Builder::new()
     .prefix("random-access-disk")
@@ -371,3 +371,861 @@ async fn can_del_long_more_than_block() {
     .unwrap();
   assert_eq!(5, file.len().await.unwrap());
 }
+
+#[apply(async_test!)]
+async fn read_errors_when_file_shrinks_on_disk() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let mut file = rad::RandomAccessDisk::open(dir.path().join("17.db"))
+    .await
+    .unwrap();
+  file.write(0, b"hello world").await.unwrap();
+  std::fs::OpenOptions::new()
+    .write(true)
+    .open(dir.path().join("17.db"))
+    .unwrap()
+    .set_len(5)
+    .unwrap();
+  assert_eq!(file.read(0, 5).await.unwrap(), b"hello");
+  let err = file.read(0, 11).await.unwrap_err();
+  assert!(matches!(
+    rad::DiskError::from_random_access_error(&err),
+    Some(rad::DiskError::ShortRead {
+      offset: 0,
+      expected: 11,
+      actual: 5,
+    })
+  ));
+}
+
+#[apply(async_test!)]
+async fn can_open_read_only() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let mut file = rad::RandomAccessDisk::open(dir.path().join("18.db"))
+    .await
+    .unwrap();
+  file.write(0, b"hello world").await.unwrap();
+  drop(file);
+
+  let mut file = rad::RandomAccessDisk::builder(dir.path().join("18.db"))
+    .read_only(true)
+    .build()
+    .await
+    .unwrap();
+  assert_eq!(file.read(0, 11).await.unwrap(), b"hello world");
+  for err in [
+    file.write(0, b"bye").await.unwrap_err(),
+    file.del(0, 5).await.unwrap_err(),
+    file.truncate(0).await.unwrap_err(),
+  ] {
+    assert!(matches!(
+      rad::DiskError::from_random_access_error(&err),
+      Some(rad::DiskError::ReadOnly)
+    ));
+  }
+  file.sync_all().await.unwrap();
+  assert_eq!(file.len().await.unwrap(), 11);
+}
+
+#[apply(async_test!)]
+async fn read_only_does_not_create() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("missing").join("19.db");
+  assert!(rad::RandomAccessDisk::builder(&path)
+    .read_only(true)
+    .build()
+    .await
+    .is_err());
+  assert!(!path.parent().unwrap().exists());
+}
+
+#[apply(async_test!)]
+async fn open_policy_must_exist() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("20.db");
+  let err = rad::RandomAccessDisk::builder(&path)
+    .open_policy(rad::OpenPolicy::MustExist)
+    .build()
+    .await
+    .unwrap_err();
+  assert!(matches!(
+    rad::DiskError::from_random_access_error(&err),
+    Some(rad::DiskError::NotFound { .. })
+  ));
+  assert!(!path.exists());
+
+  rad::RandomAccessDisk::open(&path)
+    .await
+    .unwrap()
+    .write(0, b"hello")
+    .await
+    .unwrap();
+  let mut file = rad::RandomAccessDisk::builder(&path)
+    .open_policy(rad::OpenPolicy::MustExist)
+    .build()
+    .await
+    .unwrap();
+  assert_eq!(file.read(0, 5).await.unwrap(), b"hello");
+}
+
+#[apply(async_test!)]
+async fn open_policy_must_be_new() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("21.db");
+  let mut file = rad::RandomAccessDisk::builder(&path)
+    .open_policy(rad::OpenPolicy::MustBeNew)
+    .build()
+    .await
+    .unwrap();
+  file.write(0, b"hello").await.unwrap();
+  drop(file);
+  let err = rad::RandomAccessDisk::builder(&path)
+    .open_policy(rad::OpenPolicy::MustBeNew)
+    .build()
+    .await
+    .unwrap_err();
+  assert!(matches!(
+    rad::DiskError::from_random_access_error(&err),
+    Some(rad::DiskError::AlreadyExists { .. })
+  ));
+}
+
+#[apply(async_test!)]
+async fn open_policy_truncate_existing() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("22.db");
+  let err = rad::RandomAccessDisk::builder(&path)
+    .open_policy(rad::OpenPolicy::TruncateExisting)
+    .build()
+    .await
+    .unwrap_err();
+  assert!(matches!(
+    rad::DiskError::from_random_access_error(&err),
+    Some(rad::DiskError::NotFound { .. })
+  ));
+
+  rad::RandomAccessDisk::open(&path)
+    .await
+    .unwrap()
+    .write(0, b"hello")
+    .await
+    .unwrap();
+  let mut file = rad::RandomAccessDisk::builder(&path)
+    .open_policy(rad::OpenPolicy::TruncateExisting)
+    .build()
+    .await
+    .unwrap();
+  assert!(file.is_empty().await.unwrap());
+}
+
+#[apply(async_test!)]
+async fn exclusive_lock_keeps_others_out() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("23.db");
+  let file = rad::RandomAccessDisk::builder(&path)
+    .lock(rad::LockMode::Exclusive)
+    .build()
+    .await
+    .unwrap();
+  for mode in [rad::LockMode::Exclusive, rad::LockMode::Shared] {
+    let err = rad::RandomAccessDisk::builder(&path)
+      .lock(mode)
+      .lock_timeout(std::time::Duration::from_millis(20))
+      .build()
+      .await
+      .unwrap_err();
+    assert!(matches!(
+      rad::DiskError::from_random_access_error(&err),
+      Some(rad::DiskError::Locked { .. })
+    ));
+  }
+  drop(file);
+  rad::RandomAccessDisk::builder(&path)
+    .lock(rad::LockMode::Exclusive)
+    .build()
+    .await
+    .unwrap();
+}
+
+#[apply(async_test!)]
+async fn shared_locks_can_be_held_together() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("24.db");
+  let _first = rad::RandomAccessDisk::builder(&path)
+    .lock(rad::LockMode::Shared)
+    .build()
+    .await
+    .unwrap();
+  let _second = rad::RandomAccessDisk::builder(&path)
+    .lock(rad::LockMode::Shared)
+    .read_only(true)
+    .build()
+    .await
+    .unwrap();
+  assert!(rad::RandomAccessDisk::builder(&path)
+    .lock(rad::LockMode::Exclusive)
+    .build()
+    .await
+    .is_err());
+}
+
+#[apply(async_test!)]
+async fn close_syncs_and_disables() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("25.db");
+  let mut file = rad::RandomAccessDisk::builder(&path)
+    .lock(rad::LockMode::Exclusive)
+    .build()
+    .await
+    .unwrap();
+  file.write(0, b"hello").await.unwrap();
+  file.close().await.unwrap();
+  assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+  for err in [
+    file.write(0, b"hello").await.unwrap_err(),
+    file.read(0, 5).await.unwrap_err(),
+    file.len().await.unwrap_err(),
+  ] {
+    assert!(matches!(
+      rad::DiskError::from_random_access_error(&err),
+      Some(rad::DiskError::Closed)
+    ));
+  }
+  file.close().await.unwrap();
+  // The lock was released even though `file` is still around.
+  rad::RandomAccessDisk::builder(&path)
+    .lock(rad::LockMode::Exclusive)
+    .build()
+    .await
+    .unwrap();
+}
+
+#[apply(async_test!)]
+async fn debug_panic_drop_strategy() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let mut file = rad::RandomAccessDisk::builder(dir.path().join("26.db"))
+    .auto_sync(false)
+    .drop_strategy(rad::DropStrategy::DebugPanic)
+    .build()
+    .await
+    .unwrap();
+  file.write(0, b"hello").await.unwrap();
+  file.sync_all().await.unwrap();
+  // Nothing to sync, so no panic.
+  drop(file);
+
+  let mut file = rad::RandomAccessDisk::builder(dir.path().join("26.db"))
+    .auto_sync(false)
+    .drop_strategy(rad::DropStrategy::DebugPanic)
+    .build()
+    .await
+    .unwrap();
+  file.write(0, b"hello").await.unwrap();
+  let dropped =
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(file)));
+  assert_eq!(dropped.is_err(), cfg!(debug_assertions));
+}
+
+#[apply(async_test!)]
+async fn spawn_drop_strategy() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let mut file = rad::RandomAccessDisk::builder(dir.path().join("27.db"))
+    .auto_sync(false)
+    .drop_strategy(rad::DropStrategy::Spawn)
+    .build()
+    .await
+    .unwrap();
+  file.write(0, b"hello").await.unwrap();
+  drop(file);
+  let mut file = rad::RandomAccessDisk::open(dir.path().join("27.db"))
+    .await
+    .unwrap();
+  assert_eq!(file.read(0, 5).await.unwrap(), b"hello");
+}
+
+#[apply(async_test!)]
+async fn sync_policies() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("28.db");
+  // DebugPanic tells whether there were unsynced changes left on drop.
+  let leaves_unsynced = |file: rad::RandomAccessDisk| {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| drop(file)))
+      .is_err()
+      && cfg!(debug_assertions)
+  };
+  let open = |sync_policy| {
+    rad::RandomAccessDisk::builder(&path)
+      .sync_policy(sync_policy)
+      .drop_strategy(rad::DropStrategy::DebugPanic)
+      .build()
+  };
+
+  let mut file = open(rad::SyncPolicy::DataOnly).await.unwrap();
+  file.write(0, b"hello").await.unwrap();
+  assert!(!leaves_unsynced(file));
+
+  let mut file = open(rad::SyncPolicy::EveryN(2)).await.unwrap();
+  file.write(0, b"hello").await.unwrap();
+  file.write(5, b" world").await.unwrap();
+  assert!(!leaves_unsynced(file));
+  let mut file = open(rad::SyncPolicy::EveryN(2)).await.unwrap();
+  file.write(0, b"hello").await.unwrap();
+  file.write(5, b" world").await.unwrap();
+  file.del(0, 2).await.unwrap();
+  assert_eq!(leaves_unsynced(file), cfg!(debug_assertions));
+
+  let mut file =
+    open(rad::SyncPolicy::EveryDuration(std::time::Duration::ZERO))
+      .await
+      .unwrap();
+  file.truncate(3).await.unwrap();
+  assert!(!leaves_unsynced(file));
+
+  let mut file = open(rad::SyncPolicy::Manual).await.unwrap();
+  file.write(0, b"hello").await.unwrap();
+  file.sync_all().await.unwrap();
+  assert!(!leaves_unsynced(file));
+}
+
+#[apply(async_test!)]
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+async fn direct_io_handles_unaligned_access() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("29.db");
+  let mut file = rad::RandomAccessDisk::builder(&path)
+    .direct_io(true)
+    .build()
+    .await
+    .unwrap();
+  let mut model = vec![0_u8; 10000];
+  for (i, byte) in model.iter_mut().enumerate() {
+    *byte = (i % 251) as u8 + 1;
+  }
+  file.write(3, &model[3..9000]).await.unwrap();
+  file.write(0, &model[..3]).await.unwrap();
+  file.write(9000, &model[9000..]).await.unwrap();
+  assert_eq!(file.len().await.unwrap(), 10000);
+  assert_eq!(file.read(0, 10000).await.unwrap(), model);
+  assert_eq!(file.read(4095, 3).await.unwrap(), &model[4095..4098]);
+  let batch = file.read_batch(&[(4095, 3), (0, 10)]).await;
+  assert_eq!(batch[0].as_ref().unwrap(), &model[4095..4098]);
+  assert_eq!(batch[1].as_ref().unwrap(), &model[..10]);
+
+  file.del(100, 9000).await.unwrap();
+  model[100..9100].fill(0);
+  assert_eq!(file.read(0, 10000).await.unwrap(), model);
+  file.truncate(5000).await.unwrap();
+  file.write(4999, b"ab").await.unwrap();
+  model.truncate(4999);
+  model.extend_from_slice(b"ab");
+  file.close().await.unwrap();
+  assert_eq!(std::fs::read(&path).unwrap(), model);
+
+  let err = rad::RandomAccessDisk::builder(&path)
+    .direct_io(true)
+    .build_shared()
+    .await
+    .unwrap_err();
+  assert!(matches!(
+    rad::DiskError::from_random_access_error(&err),
+    Some(rad::DiskError::IncompatibleOptions(_))
+  ));
+}
+
+#[apply(async_test!)]
+async fn can_write_batch() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("30.db");
+  let mut file = rad::RandomAccessDisk::builder(&path)
+    .sync_policy(rad::SyncPolicy::EveryN(2))
+    .drop_strategy(rad::DropStrategy::DebugPanic)
+    .build()
+    .await
+    .unwrap();
+  file
+    .write_batch(&[(6, b"world"), (0, b"hello"), (5, b" "), (11, b"!")])
+    .await
+    .unwrap();
+  assert_eq!(file.read(0, 12).await.unwrap(), b"hello world!");
+
+  // Overlapping writes land in order, empty ones past the end still grow.
+  file
+    .write_batch(&[(3, b"p me"), (0, b"hel"), (4, b" us"), (20, b"")])
+    .await
+    .unwrap();
+  assert_eq!(file.len().await.unwrap(), 20);
+  assert_eq!(file.read(0, 12).await.unwrap(), b"help usorld!");
+  assert_eq!(file.read(12, 8).await.unwrap(), [0; 8]);
+
+  // Each batch counts as one change, so the second one synced everything.
+  drop(file);
+  let mut file = rad::RandomAccessDisk::builder(&path)
+    .sync_policy(rad::SyncPolicy::EveryN(2))
+    .drop_strategy(rad::DropStrategy::DebugPanic)
+    .build()
+    .await
+    .unwrap();
+  file.write_batch(&[]).await.unwrap();
+  file.sync_all().await.unwrap();
+}
+
+#[apply(async_test!)]
+async fn can_read_into_and_read_batch() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let mut file = rad::RandomAccessDisk::open(dir.path().join("31.db"))
+    .await
+    .unwrap();
+  let data: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+  file.write(0, &data).await.unwrap();
+
+  let mut buffer = [0; 40];
+  file.read_into(1000, &mut buffer).await.unwrap();
+  assert_eq!(buffer, data[1000..1040]);
+  file.read_into(19990, &mut buffer[..10]).await.unwrap();
+  assert_eq!(buffer[..10], data[19990..]);
+  assert!(file.read_into(19990, &mut buffer).await.is_err());
+
+  let ranges = [
+    (5000, 40),
+    (0, 40),
+    (40, 40),
+    (100, 40),
+    (120, 40),
+    (19000, 1000),
+    (100, 0),
+    (19990, 20),
+  ];
+  let results = file.read_batch(&ranges).await;
+  assert_eq!(results.len(), ranges.len());
+  for (&(offset, length), result) in ranges.iter().zip(results) {
+    let end = offset + length;
+    if end > 20000 {
+      assert!(result.is_err());
+    } else {
+      assert_eq!(result.unwrap(), &data[offset as usize..end as usize]);
+    }
+  }
+}
+
+#[apply(async_test!)]
+async fn write_buffer_keeps_writes_until_flushed() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("32.db");
+  let on_disk = || std::fs::read(&path).unwrap();
+  let mut file = rad::RandomAccessDisk::builder(&path)
+    .write_buffer(100)
+    .build()
+    .await
+    .unwrap();
+  file.write(0, b"hello").await.unwrap();
+  file.write(5, b" world").await.unwrap();
+  file.write(20, b"").await.unwrap();
+  assert_eq!(on_disk(), b"");
+  assert_eq!(file.len().await.unwrap(), 20);
+  assert_eq!(file.read(3, 5).await.unwrap(), b"lo wo");
+  assert_eq!(file.read(8, 12).await.unwrap(), b"rld\0\0\0\0\0\0\0\0\0");
+
+  file.sync_all().await.unwrap();
+  assert_eq!(on_disk(), b"hello world\0\0\0\0\0\0\0\0\0");
+  file.write(0, b"j").await.unwrap();
+  assert_eq!(file.read(0, 5).await.unwrap(), b"jello");
+  file.del(1, 2).await.unwrap();
+  assert_eq!(on_disk(), b"j\0\0lo world\0\0\0\0\0\0\0\0\0");
+
+  // Reaching the threshold flushes.
+  file.write(0, &[1; 100]).await.unwrap();
+  assert_eq!(on_disk()[..100], [1; 100]);
+
+  // So does dropping, whatever the drop strategy.
+  file.write(100, b"!").await.unwrap();
+  file.truncate(50).await.unwrap();
+  file.write(50, b"bye").await.unwrap();
+  drop(file);
+  let mut file = rad::RandomAccessDisk::builder(&path)
+    .drop_strategy(rad::DropStrategy::Warn)
+    .write_buffer(100)
+    .build()
+    .await
+    .unwrap();
+  assert_eq!(file.len().await.unwrap(), 53);
+  assert_eq!(file.read(48, 5).await.unwrap(), b"\x01\x01bye");
+  file.write(53, b"!").await.unwrap();
+  drop(file);
+  assert_eq!(on_disk()[48..], *b"\x01\x01bye!");
+}
+
+#[apply(async_test!)]
+async fn write_buffer_keeps_writes_when_flushing_fails() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let mut file = rad::RandomAccessDisk::builder(dir.path().join("42.db"))
+    .drop_strategy(rad::DropStrategy::Warn)
+    .write_buffer(100)
+    .build()
+    .await
+    .unwrap();
+  file.write(0, b"hello").await.unwrap();
+  // Past the largest offset files can have.
+  file.write(i64::MAX as u64, b"!").await.unwrap();
+  assert!(file.sync_all().await.is_err());
+  assert_eq!(file.read(0, 5).await.unwrap(), b"hello");
+  assert_eq!(file.read(i64::MAX as u64, 1).await.unwrap(), b"!");
+  assert!(file.sync_all().await.is_err());
+}
+
+#[apply(async_test!)]
+async fn read_cache_counts_hits_and_sees_changes() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  for policy in [rad::EvictionPolicy::Lru, rad::EvictionPolicy::Clock] {
+    let path = dir.path().join(format!("33-{policy:?}.db"));
+    let mut file = rad::RandomAccessDisk::builder(&path)
+      .read_cache(32)
+      .read_cache_block_size(8)
+      .read_cache_eviction(policy)
+      .build()
+      .await
+      .unwrap();
+    let stats = |file: &rad::RandomAccessDisk| {
+      let stats = file.read_cache_stats().unwrap();
+      (stats.hits, stats.misses)
+    };
+    file.write(0, b"hello world, how are you?").await.unwrap();
+    assert_eq!(file.read(0, 11).await.unwrap(), b"hello world");
+    assert_eq!(stats(&file), (0, 2));
+    assert_eq!(file.read(6, 5).await.unwrap(), b"world");
+    assert_eq!(stats(&file), (2, 2));
+
+    // Changes show up in cached blocks.
+    file.write(6, b"there").await.unwrap();
+    file.del(1, 2).await.unwrap();
+    assert_eq!(file.read(0, 11).await.unwrap(), b"h\0\0lo there");
+    assert_eq!(stats(&file), (4, 2));
+    file.truncate(10).await.unwrap();
+    file.write(12, b"!").await.unwrap();
+    assert_eq!(file.read(8, 5).await.unwrap(), b"er\0\0!");
+
+    // Only four blocks fit, so reading a fifth drops one.
+    assert_eq!(file.read(0, 13).await.unwrap(), b"h\0\0lo ther\0\0!");
+    file.write(40, b"end").await.unwrap();
+    let (hits, misses) = stats(&file);
+    assert_eq!(file.read(16, 27).await.unwrap()[24..], *b"end");
+    assert_eq!(stats(&file), (hits, misses + 4));
+    file.read(0, 16).await.unwrap();
+    let (_, misses_after) = stats(&file);
+    assert!(misses_after > misses + 4);
+    file.close().await.unwrap();
+    assert_eq!(std::fs::read(&path).unwrap()[..13], *b"h\0\0lo ther\0\0!");
+  }
+}
+
+#[apply(async_test!)]
+async fn can_find_data_ranges_and_holes() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let mut file = rad::RandomAccessDisk::open(dir.path().join("34.db"))
+    .await
+    .unwrap();
+  const K64: u64 = 1 << 16;
+  file.write(0, &[1; 3 * K64 as usize]).await.unwrap();
+  file.del(K64, K64).await.unwrap();
+  file.write(16 * K64, &[2; 100]).await.unwrap();
+  let length = 16 * K64 + 100;
+
+  let data = file.data_ranges(0, u64::MAX).await.unwrap();
+  let holes = file.holes(0, u64::MAX).await.unwrap();
+  #[cfg(all(feature = "sparse", target_os = "linux"))]
+  {
+    assert_eq!(data, [0..K64, 2 * K64..3 * K64, 16 * K64..length]);
+    assert_eq!(holes, [K64..2 * K64, 3 * K64..16 * K64]);
+    let data = file.data_ranges(K64 / 2, K64).await.unwrap();
+    assert_eq!(data.len(), 1);
+    assert_eq!(data[0], K64 / 2..K64);
+  }
+  // Either way, they make up the whole file between them.
+  let mut ranges: Vec<_> = data.into_iter().chain(holes).collect();
+  ranges.sort_by_key(|range| range.start);
+  assert_eq!(ranges[0].start, 0);
+  assert_eq!(ranges.last().unwrap().end, length);
+  assert!(ranges.windows(2).all(|pair| pair[0].end == pair[1].start));
+
+  assert!(file.data_ranges(length, 10).await.unwrap().is_empty());
+}
+
+#[apply(async_test!)]
+async fn allocated_size_shrinks_with_holes() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let mut file = rad::RandomAccessDisk::open(dir.path().join("35.db"))
+    .await
+    .unwrap();
+  assert_eq!(file.allocated_size().await.unwrap(), 0);
+  file.write(0, &[1; 1 << 20]).await.unwrap();
+  let full = file.allocated_size().await.unwrap();
+  assert!(full >= 1 << 20);
+  file.del(1 << 18, 1 << 19).await.unwrap();
+  file.write(1 << 24, b"end").await.unwrap();
+  let allocated = file.allocated_size().await.unwrap();
+  #[cfg(all(feature = "sparse", target_os = "linux"))]
+  assert!(allocated < full, "{allocated} < {full}");
+  assert!(allocated <= file.len().await.unwrap());
+}
+
+#[apply(async_test!)]
+async fn can_preallocate() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("36.db");
+  let mut file = rad::RandomAccessDisk::builder(&path)
+    .preallocate(1 << 20)
+    .build()
+    .await
+    .unwrap();
+  assert_eq!(file.len().await.unwrap(), 0);
+  file.preallocate(1 << 20, 1 << 20).await.unwrap();
+  file.write(0, b"hello").await.unwrap();
+  assert_eq!(file.len().await.unwrap(), 5);
+  assert_eq!(file.read(0, 5).await.unwrap(), b"hello");
+  #[cfg(all(feature = "sparse", target_os = "linux"))]
+  {
+    assert!(file.allocated_size().await.unwrap() >= 2 << 20);
+    // Out of space, or too big a file, right away.
+    assert!(file.preallocate(0, 1 << 50).await.is_err());
+  }
+  file.close().await.unwrap();
+  assert_eq!(std::fs::read(&path).unwrap(), b"hello");
+
+  let err = rad::RandomAccessDisk::builder(&path)
+    .read_only(true)
+    .preallocate(1 << 20)
+    .build()
+    .await
+    .unwrap_err();
+  assert!(matches!(
+    rad::DiskError::from_random_access_error(&err),
+    Some(rad::DiskError::IncompatibleOptions(_))
+  ));
+}
+
+#[apply(async_test!)]
+async fn zero_keeps_length_and_space() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let mut file = rad::RandomAccessDisk::open(dir.path().join("37.db"))
+    .await
+    .unwrap();
+  const K64: u64 = 1 << 16;
+  file.write(0, &[1; 3 * K64 as usize]).await.unwrap();
+  file.del(K64, K64).await.unwrap();
+  file.zero(K64 / 2, 5 * K64 / 2).await.unwrap();
+  assert_eq!(file.len().await.unwrap(), 3 * K64);
+  let read = file.read(0, 3 * K64).await.unwrap();
+  assert!(read[..K64 as usize / 2].iter().all(|&b| b == 1));
+  assert!(read[K64 as usize / 2..].iter().all(|&b| b == 0));
+  // The hole was filled in again.
+  assert!(file.allocated_size().await.unwrap() >= 3 * K64);
+  assert!(file.zero(3 * K64, 1).await.is_err());
+}
+
+#[apply(async_test!)]
+async fn del_works_in_either_trim_mode() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  for mode in [rad::TrimMode::Strict, rad::TrimMode::BestEffort] {
+    let path = dir.path().join(format!("38-{mode:?}.db"));
+    let mut file = rad::RandomAccessDisk::builder(&path)
+      .trim_mode(mode)
+      .build()
+      .await
+      .unwrap();
+    file.write(0, &[1; 3 << 16]).await.unwrap();
+    match file.del(100, 2 << 16).await {
+      Ok(()) => {}
+      // Where hole punching isn't supported, only best effort works, and
+      // strict fails with the file system's error.
+      Err(err) if mode == rad::TrimMode::Strict => {
+        #[cfg(unix)]
+        assert!(
+          matches!(
+            &err,
+            random_access_storage::RandomAccessError::IO { source, .. }
+              if source.raw_os_error().is_some_and(|code| {
+                [libc::EOPNOTSUPP, libc::ENOTSUP, libc::ENOSYS].contains(&code)
+              })
+          ),
+          "{err:?}"
+        );
+        continue;
+      }
+      Err(err) => panic!("{err:?}"),
+    }
+    file.del(1 << 17, 10).await.unwrap();
+    let read = file.read(0, 3 << 16).await.unwrap();
+    assert!(read[..100].iter().all(|&b| b == 1));
+    assert!(read[100..100 + (2 << 16)].iter().all(|&b| b == 0));
+    assert!(read[100 + (2 << 16)..].iter().all(|&b| b == 1));
+  }
+}
+
+#[test]
+fn works_with_any_executor() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("39.db");
+  let check = || async {
+    let mut file = rad::RandomAccessDisk::builder(&path)
+      .drop_strategy(rad::DropStrategy::Spawn)
+      .sync_policy(rad::SyncPolicy::Manual)
+      .build()
+      .await
+      .unwrap();
+    file.write(0, b"hello").await.unwrap();
+    assert_eq!(file.read(0, 5).await.unwrap(), b"hello");
+  };
+  // Not within a tokio runtime, whatever runtime features are enabled.
+  async_std::task::block_on(check());
+  smol::block_on(check());
+}
+
+fn is_modified_externally(
+  err: &random_access_storage::RandomAccessError,
+) -> bool {
+  matches!(
+    rad::DiskError::from_random_access_error(err),
+    Some(rad::DiskError::ModifiedExternally { .. })
+  )
+}
+
+#[apply(async_test!)]
+async fn refresh_and_revalidate_see_external_changes() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("40.db");
+  let mut file = rad::RandomAccessDisk::builder(&path)
+    .revalidate(true)
+    .build()
+    .await
+    .unwrap();
+  file.write(0, b"hello").await.unwrap();
+  assert_eq!(file.read(0, 5).await.unwrap(), b"hello");
+
+  // Appended to by someone else.
+  let mut other = std::fs::OpenOptions::new()
+    .append(true)
+    .open(&path)
+    .unwrap();
+  std::io::Write::write_all(&mut other, b" world").unwrap();
+  assert!(is_modified_externally(&file.read(0, 5).await.unwrap_err()));
+  let mut buffer = [0; 5];
+  assert!(is_modified_externally(
+    &file.read_into(0, &mut buffer).await.unwrap_err()
+  ));
+  file.refresh().await.unwrap();
+  assert_eq!(file.len().await.unwrap(), 11);
+  assert_eq!(file.read(0, 11).await.unwrap(), b"hello world");
+
+  // Our own changes are fine.
+  file.write(11, b"!").await.unwrap();
+  file.truncate(6).await.unwrap();
+  assert_eq!(file.read(0, 6).await.unwrap(), b"hello ");
+
+  // Truncated by someone else.
+  other.set_len(2).unwrap();
+  assert!(is_modified_externally(&file.read(0, 1).await.unwrap_err()));
+  file.refresh().await.unwrap();
+  assert_eq!(file.len().await.unwrap(), 2);
+  assert_eq!(file.read(0, 2).await.unwrap(), b"he");
+
+  // Replaced by someone else.
+  let replacement = dir.path().join("40-new.db");
+  std::fs::write(&replacement, b"other").unwrap();
+  std::fs::rename(&replacement, &path).unwrap();
+  #[cfg(unix)]
+  assert!(is_modified_externally(&file.read(0, 2).await.unwrap_err()));
+  assert!(is_modified_externally(&file.refresh().await.unwrap_err()));
+}
+
+#[apply(async_test!)]
+async fn refresh_drops_read_cache() {
+  let dir = Builder::new()
+    .prefix("random-access-disk")
+    .tempdir()
+    .unwrap();
+  let path = dir.path().join("41.db");
+  let mut file = rad::RandomAccessDisk::builder(&path)
+    .read_cache(4096)
+    .build()
+    .await
+    .unwrap();
+  file.write(0, b"hello").await.unwrap();
+  assert_eq!(file.read(0, 5).await.unwrap(), b"hello");
+  std::fs::write(&path, b"jelly").unwrap();
+  assert_eq!(file.read(0, 5).await.unwrap(), b"hello");
+  file.refresh().await.unwrap();
+  assert_eq!(file.read(0, 5).await.unwrap(), b"jelly");
+}